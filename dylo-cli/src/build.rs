@@ -0,0 +1,71 @@
+//! Generates consumers from their build script, for mods whose manifest has
+//! `[package.metadata.dylo] build-script = true`. `dylo gen` then only writes stubs in
+//! the consumer: a `build.rs` that calls [`consumer`], and a `src/lib.rs` that includes
+//! what it generates in `OUT_DIR`. Since cargo reruns the build script whenever the mod's
+//! sources change, the consumer can't get out of date.
+//!
+//! The mod's own generated files (`src/.dylo/spec.rs`) are left to `dylo gen`: build
+//! scripts must not write outside of `OUT_DIR` (`cargo package` rejects crates whose
+//! build script does), and the mod is built on its own, maybe before its consumer. The
+//! build script only warns about them being out of date, as does the mod when it builds.
+
+use std::time::SystemTime;
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{codegen::Generator, error::Error, types::ModInfo};
+
+/// Whether the mod's manifest has `[package.metadata.dylo] build-script = true`.
+pub(crate) fn build_script_enabled(doc: &toml_edit::DocumentMut) -> bool {
+    doc.get("package")
+        .and_then(|p| p.get("metadata"))
+        .and_then(|m| m.get("dylo"))
+        .and_then(|d| d.get("build-script"))
+        .and_then(|b| b.as_bool())
+        .unwrap_or(false)
+}
+
+/// Generates the consumer of the mod in `mod_dir` (relative to the consumer's directory)
+/// into `OUT_DIR/dylo`. Meant to be called from the consumer's build script.
+pub fn consumer(mod_dir: impl AsRef<Utf8Path>) -> Result<(), Error> {
+    let env = |var| std::env::var(var).map_err(|_| Error::Env { var });
+    let con_path = Utf8PathBuf::from(env("CARGO_MANIFEST_DIR")?);
+    let out_dir = Utf8PathBuf::from(env("OUT_DIR")?).join("dylo");
+    // `../mod-foo` from the consumer: its parent is the workspace, its name is the mod's
+    let mod_path = con_path.join(mod_dir.as_ref()).canonicalize_utf8()?;
+
+    println!("cargo:rerun-if-changed={}", mod_path.join("src"));
+    println!("cargo:rerun-if-changed={}", mod_path.join("Cargo.toml"));
+
+    let mod_info = ModInfo {
+        name: mod_path
+            .file_name()
+            .unwrap_or_default()
+            .trim_start_matches("mod-")
+            .to_string(),
+        mod_path: mod_path.clone(),
+        con_path,
+        mod_timestamp: SystemTime::UNIX_EPOCH,
+        con_timestamp: SystemTime::UNIX_EPOCH,
+    };
+    let workspace_root = mod_path.parent().unwrap_or(&mod_path);
+    let generated = Generator::new(workspace_root).generate(&mod_info)?;
+    let Some(out_dir_files) = generated.out_dir_files else {
+        return Err(Error::Manifest {
+            path: mod_path.join("Cargo.toml"),
+            message: "package.metadata.dylo.build-script must be true for the consumer to be generated by its build script".to_string(),
+        });
+    };
+
+    for path in generated.mod_files.different_files(&mod_path)? {
+        println!(
+            "cargo:warning={} is out of date, run `dylo gen` to regenerate it",
+            mod_path.join(path)
+        );
+    }
+
+    if out_dir_files.is_different(&out_dir)? {
+        out_dir_files.commit(&out_dir)?;
+    }
+    Ok(())
+}
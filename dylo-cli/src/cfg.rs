@@ -0,0 +1,417 @@
+//! Partial evaluation of `cfg` predicates for the consumer version of a mod, where
+//! `feature = "impl"` (and `test`) are known to be disabled, and everything else
+//! is left for rustc to decide.
+
+use proc_macro2::TokenStream;
+use quote::{ToTokens, quote};
+use syn::{
+    Attribute, Meta,
+    punctuated::{Pair, Punctuated},
+    visit_mut::{self, VisitMut},
+};
+
+use crate::codegen::item_attributes;
+
+/// A `cfg` predicate, simplified as far as we can take it.
+#[derive(Clone)]
+enum Cfg {
+    True,
+    False,
+    /// Anything we don't know the value of, like `unix` or `feature = "tokio"`
+    Unknown(Box<Meta>),
+    All(Vec<Cfg>),
+    Any(Vec<Cfg>),
+    Not(Box<Cfg>),
+}
+
+impl Cfg {
+    /// Parses and simplifies a predicate. Malformed predicates are kept as-is:
+    /// rustc will complain about them, we don't have to.
+    fn eval(meta: &Meta) -> Cfg {
+        match meta {
+            Meta::Path(path) if path.is_ident("test") => Cfg::False,
+            Meta::NameValue(nv) if nv.path.is_ident("feature") => match &nv.value {
+                syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Str(lit),
+                    ..
+                }) if lit.value() == "impl" => Cfg::False,
+                _ => Cfg::Unknown(Box::new(meta.clone())),
+            },
+            Meta::List(list) => {
+                let Ok(nested) =
+                    list.parse_args_with(Punctuated::<Meta, syn::Token![,]>::parse_terminated)
+                else {
+                    return Cfg::Unknown(Box::new(meta.clone()));
+                };
+                let nested: Vec<Cfg> = nested.iter().map(Cfg::eval).collect();
+
+                if list.path.is_ident("all") {
+                    Cfg::all(nested)
+                } else if list.path.is_ident("any") {
+                    Cfg::any(nested)
+                } else if list.path.is_ident("not") && nested.len() == 1 {
+                    Cfg::not(nested.into_iter().next().unwrap())
+                } else {
+                    Cfg::Unknown(Box::new(meta.clone()))
+                }
+            }
+            _ => Cfg::Unknown(Box::new(meta.clone())),
+        }
+    }
+
+    fn all(nested: Vec<Cfg>) -> Cfg {
+        if nested.contains(&Cfg::False) {
+            return Cfg::False;
+        }
+        let mut nested: Vec<Cfg> = nested.into_iter().filter(|c| *c != Cfg::True).collect();
+        match nested.len() {
+            0 => Cfg::True,
+            1 => nested.pop().unwrap(),
+            _ => Cfg::All(nested),
+        }
+    }
+
+    fn any(nested: Vec<Cfg>) -> Cfg {
+        if nested.contains(&Cfg::True) {
+            return Cfg::True;
+        }
+        let mut nested: Vec<Cfg> = nested.into_iter().filter(|c| *c != Cfg::False).collect();
+        match nested.len() {
+            0 => Cfg::False,
+            1 => nested.pop().unwrap(),
+            _ => Cfg::Any(nested),
+        }
+    }
+
+    fn not(inner: Cfg) -> Cfg {
+        match inner {
+            Cfg::True => Cfg::False,
+            Cfg::False => Cfg::True,
+            Cfg::Not(inner) => *inner,
+            inner => Cfg::Not(Box::new(inner)),
+        }
+    }
+}
+
+impl PartialEq for Cfg {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_token_stream().to_string() == other.to_token_stream().to_string()
+    }
+}
+
+impl ToTokens for Cfg {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        tokens.extend(match self {
+            Cfg::True => quote! { all() },
+            Cfg::False => quote! { any() },
+            Cfg::Unknown(meta) => meta.to_token_stream(),
+            Cfg::All(nested) => quote! { all(#(#nested),*) },
+            Cfg::Any(nested) => quote! { any(#(#nested),*) },
+            Cfg::Not(inner) => quote! { not(#inner) },
+        })
+    }
+}
+
+/// Evaluates the `cfg` and `cfg_attr` attributes in `attrs` for the consumer.
+///
+/// Returns false if the item (or field, etc.) they're attached to is definitely
+/// disabled and must be removed. Otherwise, attributes that are definitely enabled
+/// are dropped (for `cfg`) or expanded (for `cfg_attr`), and the others are rewritten
+/// to whatever is left of their predicate once `feature = "impl"` is known to be disabled.
+pub(crate) fn eval_cfg_attrs(attrs: &mut Vec<Attribute>) -> bool {
+    let mut pending: Vec<Attribute> = std::mem::take(attrs);
+    pending.reverse();
+
+    while let Some(mut attr) = pending.pop() {
+        let Meta::List(list) = &attr.meta else {
+            attrs.push(attr);
+            continue;
+        };
+
+        if list.path.is_ident("cfg") {
+            let Ok(predicate) = list.parse_args::<Meta>() else {
+                attrs.push(attr);
+                continue;
+            };
+            match Cfg::eval(&predicate) {
+                Cfg::True => {}
+                Cfg::False => return false,
+                cfg => {
+                    if cfg != Cfg::Unknown(Box::new(predicate)) {
+                        // only rewrite what simplified, to keep the rest as written
+                        attr.meta = syn::parse_quote!(cfg(#cfg));
+                    }
+                    attrs.push(attr);
+                }
+            }
+        } else if list.path.is_ident("cfg_attr") {
+            let Ok(args) =
+                list.parse_args_with(Punctuated::<Meta, syn::Token![,]>::parse_terminated)
+            else {
+                attrs.push(attr);
+                continue;
+            };
+            let mut args = args.into_iter();
+            let Some(predicate) = args.next() else {
+                attrs.push(attr);
+                continue;
+            };
+            let rest: Vec<Meta> = args.collect();
+
+            match Cfg::eval(&predicate) {
+                Cfg::True => {
+                    // expanded attributes may be `cfg`s themselves, evaluate them next
+                    for meta in rest.into_iter().rev() {
+                        pending.push(Attribute {
+                            meta,
+                            ..attr.clone()
+                        });
+                    }
+                }
+                Cfg::False => {}
+                cfg => {
+                    if cfg != Cfg::Unknown(Box::new(predicate)) {
+                        attr.meta = syn::parse_quote!(cfg_attr(#cfg, #(#rest),*));
+                    }
+                    attrs.push(attr);
+                }
+            }
+        } else {
+            attrs.push(attr);
+        }
+    }
+
+    true
+}
+
+/// Removes members of `item` that are disabled in the consumer (methods of impl
+/// blocks, trait items, variants, fields, match arms, statements...), and evaluates
+/// the `cfg`s of the others. `item`'s own attributes are left alone, and so are
+/// inline modules: `transform_ast` takes care of those.
+pub(crate) fn strip_members(item: &mut syn::Item) {
+    CfgStripper.visit_item_mut(item);
+}
+
+struct CfgStripper;
+
+fn retain_vec<T>(members: &mut Vec<T>, attrs: impl Fn(&mut T) -> Option<&mut Vec<Attribute>>) {
+    members.retain_mut(|member| attrs(member).is_none_or(eval_cfg_attrs));
+}
+
+fn retain_punctuated<T, P: Default>(
+    members: &mut Punctuated<T, P>,
+    attrs: impl Fn(&mut T) -> &mut Vec<Attribute>,
+) {
+    let had_trailing_punct = members.trailing_punct();
+    *members = std::mem::take(members)
+        .into_pairs()
+        .filter_map(|pair| {
+            let (mut member, punct) = pair.into_tuple();
+            eval_cfg_attrs(attrs(&mut member))
+                .then(|| Pair::new(member, Some(punct.unwrap_or_default())))
+        })
+        .collect();
+    // don't leave a trailing punctuation behind if there wasn't one
+    if members.trailing_punct() && !had_trailing_punct {
+        if let Some(last) = members.pop() {
+            members.push(last.into_value());
+        }
+    }
+}
+
+impl VisitMut for CfgStripper {
+    fn visit_item_mod_mut(&mut self, _i: &mut syn::ItemMod) {}
+
+    fn visit_item_impl_mut(&mut self, i: &mut syn::ItemImpl) {
+        retain_vec(&mut i.items, |member| match member {
+            syn::ImplItem::Const(c) => Some(&mut c.attrs),
+            syn::ImplItem::Fn(f) => Some(&mut f.attrs),
+            syn::ImplItem::Type(t) => Some(&mut t.attrs),
+            syn::ImplItem::Macro(m) => Some(&mut m.attrs),
+            _ => None,
+        });
+        visit_mut::visit_item_impl_mut(self, i);
+    }
+
+    fn visit_item_trait_mut(&mut self, i: &mut syn::ItemTrait) {
+        retain_vec(&mut i.items, |member| match member {
+            syn::TraitItem::Const(c) => Some(&mut c.attrs),
+            syn::TraitItem::Fn(f) => Some(&mut f.attrs),
+            syn::TraitItem::Type(t) => Some(&mut t.attrs),
+            syn::TraitItem::Macro(m) => Some(&mut m.attrs),
+            _ => None,
+        });
+        visit_mut::visit_item_trait_mut(self, i);
+    }
+
+    fn visit_item_foreign_mod_mut(&mut self, i: &mut syn::ItemForeignMod) {
+        retain_vec(&mut i.items, |member| match member {
+            syn::ForeignItem::Fn(f) => Some(&mut f.attrs),
+            syn::ForeignItem::Static(s) => Some(&mut s.attrs),
+            syn::ForeignItem::Type(t) => Some(&mut t.attrs),
+            syn::ForeignItem::Macro(m) => Some(&mut m.attrs),
+            _ => None,
+        });
+        visit_mut::visit_item_foreign_mod_mut(self, i);
+    }
+
+    fn visit_item_enum_mut(&mut self, i: &mut syn::ItemEnum) {
+        retain_punctuated(&mut i.variants, |variant| &mut variant.attrs);
+        visit_mut::visit_item_enum_mut(self, i);
+    }
+
+    fn visit_fields_named_mut(&mut self, i: &mut syn::FieldsNamed) {
+        retain_punctuated(&mut i.named, |field| &mut field.attrs);
+        visit_mut::visit_fields_named_mut(self, i);
+    }
+
+    fn visit_fields_unnamed_mut(&mut self, i: &mut syn::FieldsUnnamed) {
+        retain_punctuated(&mut i.unnamed, |field| &mut field.attrs);
+        visit_mut::visit_fields_unnamed_mut(self, i);
+    }
+
+    fn visit_expr_match_mut(&mut self, i: &mut syn::ExprMatch) {
+        retain_vec(&mut i.arms, |arm| Some(&mut arm.attrs));
+        visit_mut::visit_expr_match_mut(self, i);
+    }
+
+    fn visit_expr_struct_mut(&mut self, i: &mut syn::ExprStruct) {
+        retain_punctuated(&mut i.fields, |field| &mut field.attrs);
+        visit_mut::visit_expr_struct_mut(self, i);
+    }
+
+    fn visit_pat_struct_mut(&mut self, i: &mut syn::PatStruct) {
+        retain_punctuated(&mut i.fields, |field| &mut field.attrs);
+        visit_mut::visit_pat_struct_mut(self, i);
+    }
+
+    fn visit_block_mut(&mut self, i: &mut syn::Block) {
+        retain_vec(&mut i.stmts, |stmt| match stmt {
+            syn::Stmt::Local(local) => Some(&mut local.attrs),
+            syn::Stmt::Item(item) => item_attributes(item),
+            syn::Stmt::Macro(mac) => Some(&mut mac.attrs),
+            syn::Stmt::Expr(expr, _) => expr_attributes(expr),
+        });
+        visit_mut::visit_block_mut(self, i);
+    }
+}
+
+fn expr_attributes(expr: &mut syn::Expr) -> Option<&mut Vec<Attribute>> {
+    use syn::Expr;
+    match expr {
+        Expr::Array(e) => Some(&mut e.attrs),
+        Expr::Assign(e) => Some(&mut e.attrs),
+        Expr::Async(e) => Some(&mut e.attrs),
+        Expr::Await(e) => Some(&mut e.attrs),
+        Expr::Binary(e) => Some(&mut e.attrs),
+        Expr::Block(e) => Some(&mut e.attrs),
+        Expr::Break(e) => Some(&mut e.attrs),
+        Expr::Call(e) => Some(&mut e.attrs),
+        Expr::Cast(e) => Some(&mut e.attrs),
+        Expr::Closure(e) => Some(&mut e.attrs),
+        Expr::Const(e) => Some(&mut e.attrs),
+        Expr::Continue(e) => Some(&mut e.attrs),
+        Expr::Field(e) => Some(&mut e.attrs),
+        Expr::ForLoop(e) => Some(&mut e.attrs),
+        Expr::Group(e) => Some(&mut e.attrs),
+        Expr::If(e) => Some(&mut e.attrs),
+        Expr::Index(e) => Some(&mut e.attrs),
+        Expr::Infer(e) => Some(&mut e.attrs),
+        Expr::Let(e) => Some(&mut e.attrs),
+        Expr::Lit(e) => Some(&mut e.attrs),
+        Expr::Loop(e) => Some(&mut e.attrs),
+        Expr::Macro(e) => Some(&mut e.attrs),
+        Expr::Match(e) => Some(&mut e.attrs),
+        Expr::MethodCall(e) => Some(&mut e.attrs),
+        Expr::Paren(e) => Some(&mut e.attrs),
+        Expr::Path(e) => Some(&mut e.attrs),
+        Expr::Range(e) => Some(&mut e.attrs),
+        Expr::RawAddr(e) => Some(&mut e.attrs),
+        Expr::Reference(e) => Some(&mut e.attrs),
+        Expr::Repeat(e) => Some(&mut e.attrs),
+        Expr::Return(e) => Some(&mut e.attrs),
+        Expr::Struct(e) => Some(&mut e.attrs),
+        Expr::Try(e) => Some(&mut e.attrs),
+        Expr::TryBlock(e) => Some(&mut e.attrs),
+        Expr::Tuple(e) => Some(&mut e.attrs),
+        Expr::Unary(e) => Some(&mut e.attrs),
+        Expr::Unsafe(e) => Some(&mut e.attrs),
+        Expr::While(e) => Some(&mut e.attrs),
+        Expr::Yield(e) => Some(&mut e.attrs),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(attr: &str) -> Option<String> {
+        let mut attrs = syn::parse_str::<syn::DeriveInput>(&format!("{attr} struct S;"))
+            .unwrap()
+            .attrs;
+        eval_cfg_attrs(&mut attrs).then(|| {
+            attrs
+                .iter()
+                .map(|attr| attr.to_token_stream().to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+    }
+
+    #[test]
+    fn removes_definitely_disabled_items() {
+        assert_eq!(eval(r#"#[cfg(feature = "impl")]"#), None);
+        assert_eq!(eval(r#"#[cfg(all(feature = "impl", unix))]"#), None);
+        assert_eq!(eval(r#"#[cfg(any(test, feature = "impl"))]"#), None);
+        assert_eq!(
+            eval(r#"#[cfg(not(any(unix, not(feature = "impl"))))]"#),
+            None
+        );
+    }
+
+    #[test]
+    fn drops_definitely_enabled_cfgs() {
+        assert_eq!(eval(r#"#[cfg(not(feature = "impl"))]"#), Some("".into()));
+        assert_eq!(
+            eval(r#"#[cfg(not(all(feature = "impl", unix)))]"#),
+            Some("".into())
+        );
+    }
+
+    #[test]
+    fn simplifies_the_rest() {
+        assert_eq!(
+            eval(r#"#[cfg(any(feature = "impl", unix))]"#),
+            Some("# [cfg (unix)]".into())
+        );
+        assert_eq!(
+            eval(r#"#[cfg(all(not(feature = "impl"), unix, windows))]"#),
+            Some("# [cfg (all (unix , windows))]".into())
+        );
+        assert_eq!(
+            eval(r#"#[cfg(feature = "tokio")]"#),
+            Some(r#"# [cfg (feature = "tokio")]"#.into())
+        );
+    }
+
+    #[test]
+    fn evaluates_cfg_attr() {
+        assert_eq!(
+            eval(r#"#[cfg_attr(feature = "impl", derive(Parser))]"#),
+            Some("".into())
+        );
+        assert_eq!(
+            eval(r#"#[cfg_attr(not(feature = "impl"), derive(Debug), repr(C))]"#),
+            Some("# [derive (Debug)] # [repr (C)]".into())
+        );
+        assert_eq!(
+            eval(r#"#[cfg_attr(any(feature = "impl", unix), derive(Debug))]"#),
+            Some("# [cfg_attr (unix , derive (Debug))]".into())
+        );
+        assert_eq!(
+            eval(r#"#[cfg_attr(not(feature = "impl"), cfg(feature = "impl"))]"#),
+            None
+        );
+    }
+}
@@ -1,86 +1,191 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
 
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
+use dylo_syntax::{
+    args::ExportArgs, asyncfn::desugar_async_signature, fnv1a, is_dylo_export, is_dylo_provided,
+};
 use quote::ToTokens;
-use syn::{Attribute, ImplItem, Item, Type};
+use syn::{Attribute, ImplItem, Item, Type, ext::IdentExt as _};
 
 use crate::{
-    SPEC_PATH, SUPPORT_PATH,
-    types::{DYLO_RUNTIME_VERSION, ModInfo, ProcessReason},
-    workspace::FileSet,
+    GENERATED_HEADER, SPEC_PATH, SUPPORT_PATH,
+    build::build_script_enabled,
+    cfg::{eval_cfg_attrs, strip_members},
+    diagnostics::{LineMap, SourceMap},
+    drift::declare_hash,
+    error::Error,
+    instantiate::{self, unbounded_params},
+    isolation::{generate_dispatch, generate_proxy, isolation_enabled},
+    lint::lint_exports,
+    modtree::{inline_mod_files, transform_mod_tree},
+    render::render_preserving,
+    types::{DYLO_RUNTIME_VERSION, ModInfo, Scope},
+    workspace::{FileSet, list_mods},
 };
 
-pub fn codegen_mod(
-    workspace_root: &Utf8Path,
-    mod_info: ModInfo,
-    force: bool,
-) -> std::io::Result<()> {
-    let mod_ts = mod_info
-        .mod_timestamp
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    let con_ts = mod_info
-        .con_timestamp
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    let diff = if mod_ts > con_ts {
-        format!("mod is newer by {} seconds", mod_ts - con_ts)
-    } else {
-        format!("con is newer by {} seconds", con_ts - mod_ts)
-    };
+/// Generates the files of mods and of their consumers, in memory.
+pub struct Generator {
+    workspace_root: Utf8PathBuf,
+}
 
-    tracing::debug!(
-        "Mod '{name}' in {mod_path}, {con_path}\n  mod ts = {mod_ts}\n  con ts = {con_ts}\n  {diff}",
-        name = mod_info.name,
-        mod_path = mod_info.mod_path,
-        con_path = mod_info.con_path,
-        mod_ts = mod_ts,
-        con_ts = con_ts,
-        diff = diff
-    );
+/// Everything generated for a mod, see [`Generator::generate`].
+pub struct Generated {
+    /// Files of the mod (`spec.rs`, `support.rs`, and the sources and `Cargo.toml` if
+    /// they need changes), relative to its directory
+    pub mod_files: FileSet,
+    /// Files of the consumer crate, relative to its directory
+    pub con_files: FileSet,
+    /// Where the consumer's sources come from, for diagnostics
+    pub source_map: SourceMap,
+    /// For consumers generated by their build script, what it writes to `OUT_DIR/dylo`
+    /// (`con_files` then only has stubs that include it), see [`crate::build`]
+    pub out_dir_files: Option<FileSet>,
+}
 
-    // When force is true, we regenerate regardless of timestamps
-    let reason = if force {
-        ProcessReason::Force
-    } else if !mod_info.con_path.exists() {
-        ProcessReason::Missing
-    } else if mod_info.mod_timestamp > mod_info.con_timestamp {
-        ProcessReason::Modified
-    } else {
-        // If force is false and none of the conditions above are true,
-        // no regeneration is needed
-        return Ok(());
-    };
+impl Generator {
+    pub fn new(workspace_root: impl Into<Utf8PathBuf>) -> Self {
+        Self {
+            workspace_root: workspace_root.into(),
+        }
+    }
+
+    /// Lists the mods of the workspace, or the one `scope` designates.
+    pub fn mods(&self, scope: Scope) -> Result<Vec<ModInfo>, Error> {
+        list_mods(&self.workspace_root, scope).map_err(Error::Io)
+    }
 
-    tracing::debug!("📦 Processing mod {} (because {:?})", mod_info.name, reason);
+    /// Generates the files of `mod_info` and of its consumer, regardless of timestamps.
+    /// Nothing is written to disk: see [`Generated::commit`].
+    pub fn generate(&self, mod_info: &ModInfo) -> Result<Generated, Error> {
+        generate_mod(&self.workspace_root, mod_info)
+    }
+}
+
+impl Generated {
+    /// Writes the files that differ from what's on disk. Returns whether the consumer
+    /// changed.
+    pub fn commit(&self, mod_info: &ModInfo) -> Result<bool, Error> {
+        let mod_path = Utf8Path::new(&mod_info.mod_path);
+        if self.mod_files.is_different(mod_path)? {
+            tracing::info!("📝 Changes detected in mod files for {}", mod_info.name);
+            self.mod_files.commit(mod_path)?;
+        }
+
+        let con_path = Utf8Path::new(&mod_info.con_path);
+        if !self.con_files.is_different(con_path)? {
+            return Ok(false);
+        }
+        tracing::info!(
+            "📝 Changes detected in consumer files for {}",
+            mod_info.name
+        );
+        self.con_files.commit(con_path)?;
+        Ok(true)
+    }
+
+    /// Files of the mod and of its consumer that differ from what's on disk, or that are
+    /// stale (see [`FileSet::stale_files`]), without writing anything.
+    pub fn out_of_date(&self, mod_info: &ModInfo) -> Result<Vec<Utf8PathBuf>, Error> {
+        let mut paths = Vec::new();
+        for (files, root) in [
+            (&self.mod_files, &mod_info.mod_path),
+            (&self.con_files, &mod_info.con_path),
+        ] {
+            for path in files.different_files(root)? {
+                paths.push(root.join(path));
+            }
+            for path in files.stale_files(root)? {
+                paths.push(root.join(path));
+            }
+        }
+        Ok(paths)
+    }
+}
 
+fn generate_mod(workspace_root: &Utf8Path, mod_info: &ModInfo) -> Result<Generated, Error> {
     // Generate consumer version by parsing and filtering lib.rs
     let start = std::time::Instant::now();
 
-    let lib_rs = fs_err::read_to_string(mod_info.mod_path.join("src/lib.rs"))?;
-    let ast = syn::parse_file(&lib_rs).unwrap();
-
-    let mut con_items: Vec<Item> = ast.items.clone();
-    let mut spec_items: Vec<Item> = Default::default();
-    transform_ast(&mut con_items, &mut spec_items);
+    let mut files = transform_mod_tree(&mod_info.mod_path.join("src"))?;
+
+    // Paths as cargo and rustc show them
+    let mod_rel_path = mod_info
+        .mod_path
+        .strip_prefix(workspace_root)
+        .unwrap_or(&mod_info.mod_path)
+        .join("src");
+    let con_rel_path = mod_info
+        .con_path
+        .strip_prefix(workspace_root)
+        .unwrap_or(&mod_info.con_path)
+        .join("src");
+
+    // Refuse to generate anything if exported impls can't be turned into dyn-compatible traits
+    let mut diagnostics = Vec::new();
+    for file in &files {
+        let path = mod_rel_path.join(&file.rel_path);
+        for diagnostic in lint_exports(&file.ast.items, &file.con_items) {
+            diagnostics.push(diagnostic.render(&path, &file.source));
+        }
+    }
+    if !diagnostics.is_empty() {
+        return Err(Error::NotDynCompatible {
+            mod_name: mod_info.name.clone(),
+            diagnostics,
+        });
+    }
+    let submodules = files.split_off(1);
+    let root = files.pop().unwrap();
+    let lib_rs = &root.source;
+    let mut con_items = root.con_items.clone();
+    let (root_specs, specs): (Vec<&ModuleSpec>, Vec<&ModuleSpec>) = std::iter::once(&root)
+        .chain(&submodules)
+        .flat_map(|file| &file.specs)
+        .partition(|spec| spec.is_root());
+    let spec_items: Vec<Item> = root_specs
+        .into_iter()
+        .flat_map(|spec| spec.items.clone())
+        .collect();
 
     let duration = start.elapsed();
 
     let autogen_prefix = [
-        "// This file is automatically @generated by dylo",
+        GENERATED_HEADER,
         "// To regenerate, run `dylo gen` in the root of the workspace directory",
         "// For more information, see https://github.com/bearcove/dylo",
     ]
     .join("\n");
+    let con_prefix = format!("{autogen_prefix}\n#![allow(unused_imports)]\n");
+
+    // Consumer sources, and which lines of the mod's they come from
+    let mut source_map = SourceMap::default();
+    let mut render_con = |source: &str, con_ast: &syn::File, rel_path: &Utf8Path| {
+        let (formatted, mut lines) = render_preserving(source, con_ast)
+            .unwrap_or_else(|| (prettyplease::unparse(con_ast), LineMap::default()));
+        lines.shift(con_prefix.matches('\n').count() as isize);
+        source_map.insert(
+            con_rel_path.join(rel_path),
+            mod_rel_path.join(rel_path),
+            lines,
+        );
+        format!("{con_prefix}{formatted}")
+    };
+
+    let mod_trait = spec_items.iter().find_map(|item| match item {
+        Item::Trait(trait_item) if trait_item.ident == "Mod" => Some(trait_item.clone()),
+        _ => None,
+    });
 
     let spec_ast = syn::File {
         shebang: None,
         attrs: Default::default(),
         items: spec_items,
     };
-    let spec_formatted = prettyplease::unparse(&spec_ast);
+    let mut spec_formatted = prettyplease::unparse(&spec_ast);
+    for spec in specs {
+        spec_formatted.push_str(&spec_macro(spec));
+    }
     let spec_formatted = format!("{autogen_prefix}\n{spec_formatted}");
 
     let mut missing_spec = true;
@@ -113,68 +218,94 @@ pub fn codegen_mod(
         });
     }
 
+    let mod_dir = mod_info.mod_path.file_name().unwrap_or_default();
+    let mut con_attrs = root.con_attrs.clone();
+    rebase_includes(&mut con_attrs, &root.rel_path, mod_dir);
     let con_ast = syn::File {
         shebang: None,
-        attrs: Default::default(),
+        attrs: con_attrs,
         items: con_items,
     };
-    let con_formatted = prettyplease::unparse(&con_ast);
-    let con_formatted = format!("#![allow(unused_imports)]\n{autogen_prefix}\n{con_formatted}");
+    let con_formatted = render_con(lib_rs, &con_ast, &root.rel_path);
 
     tracing::debug!(
-        "📝 Parsed {} in {:.2}s, size: {} bytes",
+        "📝 Parsed {} in {:.2}s, {} files, size: {} bytes",
         mod_info.name,
         duration.as_secs_f32(),
-        lib_rs.len()
+        submodules.len() + 1,
+        lib_rs.len() + submodules.iter().map(|f| f.source.len()).sum::<usize>()
     );
 
     // Generate files for mod version
     let mut mod_files = FileSet::new();
+    mod_files.owned.push(Utf8Path::new("src").join(".dylo"));
 
     // Check and add "dylo-runtime" dependency to Cargo.toml if needed
-    let cargo_toml = fs_err::read_to_string(mod_info.mod_path.join("Cargo.toml"))?;
-    let mut doc = cargo_toml.parse::<toml_edit::DocumentMut>().unwrap();
-
-    let mut need_dylo_runtime = true;
-    let mut current_version = None;
-    if let Some(deps) = doc.get("dependencies") {
-        if deps.is_table() {
-            let deps_table = deps.as_table().unwrap();
-            if let Some(runtime_dep) = deps_table.get("dylo-runtime") {
-                need_dylo_runtime = false;
-                current_version = runtime_dep.as_str().map(|s| s.to_string());
-            }
-        }
-    }
+    let mut doc = read_manifest(mod_info)?;
 
-    if need_dylo_runtime || current_version.as_deref() != Some(DYLO_RUNTIME_VERSION) {
-        tracing::info!(
-            "Adding or updating dylo-runtime dependency to {} for {}",
-            DYLO_RUNTIME_VERSION,
-            mod_info.name
-        );
-        if let Some(deps) = doc.get_mut("dependencies") {
-            if deps.is_table() {
-                let deps_table = deps.as_table_mut().unwrap();
-                deps_table.insert("dylo-runtime", toml_edit::value(DYLO_RUNTIME_VERSION));
+    let isolation = isolation_enabled(&doc);
+    let build_script = build_script_enabled(&doc);
+    if let Some(deps) = doc.get_mut("dependencies") {
+        if let Some(deps_table) = deps.as_table_mut() {
+            if update_dylo_runtime_dependency(deps_table, isolation) {
+                tracing::info!(
+                    "Adding or updating dylo-runtime dependency to {} for {}",
+                    DYLO_RUNTIME_VERSION,
+                    mod_info.name
+                );
                 mod_files.files.insert("Cargo.toml".into(), doc.to_string());
             }
         }
     }
 
+    // Isolated mods get a dispatcher (mod side) and a proxy (consumer side) for `Mod`
+    let (dispatch_src, proxy_src) = match (isolation, &mod_trait) {
+        (true, Some(mod_trait)) => {
+            let unparse = |items| {
+                prettyplease::unparse(&syn::File {
+                    shebang: None,
+                    attrs: Default::default(),
+                    items,
+                })
+            };
+            let isolation_error = |message| Error::Isolation {
+                mod_name: mod_info.name.clone(),
+                message,
+            };
+            let dispatch = generate_dispatch(mod_trait).map_err(isolation_error)?;
+            let proxy = generate_proxy(mod_trait).map_err(isolation_error)?;
+            (Some(unparse(dispatch)), Some(unparse(proxy)))
+        }
+        (true, None) => {
+            tracing::warn!(
+                "Isolation is enabled for {}, but it doesn't export a `Mod` trait",
+                mod_info.name
+            );
+            (None, None)
+        }
+        (false, _) => (None, None),
+    };
+
     // Add spec.rs to mod version
     mod_files
         .files
         .insert(format!("src/{SPEC_PATH}").into(), spec_formatted.clone());
 
-    let awaken_src = include_str!("templates/awaken.rs.template");
+    let mut awaken_src = format!(
+        "{autogen_prefix}\n{}",
+        include_str!("templates/awaken.rs.template")
+    );
+    if let Some(dispatch_src) = &dispatch_src {
+        awaken_src.push('\n');
+        awaken_src.push_str(dispatch_src);
+    }
     mod_files
         .files
-        .insert(format!("src/{SUPPORT_PATH}").into(), awaken_src.to_string());
+        .insert(format!("src/{SUPPORT_PATH}").into(), awaken_src);
 
     // Check for include statements for spec and support files
     let mut include_paths = HashSet::new();
-    for item in &ast.items {
+    for item in &root.ast.items {
         if let Item::Macro(mac) = item {
             if mac.mac.path.is_ident("include") {
                 if let Ok(lit) = syn::parse2::<syn::LitStr>(mac.mac.tokens.clone()) {
@@ -194,77 +325,167 @@ pub fn codegen_mod(
         added_suffixes.push(format!("include!(\"{SUPPORT_PATH}\");"));
     }
 
-    if !added_suffixes.is_empty() {
-        let suffix = format!("\n\n{}", added_suffixes.join("\n"));
-        let content = format!("{lib_rs}{suffix}");
+    // Modules (other than the root) that export traits get them by invoking a macro from spec.rs
+    let lib_rs_with_invocations = root.with_spec_invocations();
+    if !added_suffixes.is_empty() || lib_rs_with_invocations.is_some() {
+        let lib_rs = lib_rs_with_invocations.as_deref().unwrap_or(lib_rs);
+        // laid out like the consumer gets them, so the next run renders it the same
+        let content = if added_suffixes.is_empty() {
+            lib_rs.to_string()
+        } else {
+            format!("{}\n\n{}\n", lib_rs.trim_end(), added_suffixes.join("\n"))
+        };
         mod_files.files.insert("src/lib.rs".into(), content);
     }
 
+    for submodule in &submodules {
+        if let Some(content) = submodule.with_spec_invocations() {
+            mod_files
+                .files
+                .insert(Utf8Path::new("src").join(&submodule.rel_path), content);
+        }
+    }
+
     // Generate files for consumer version
     let mut con_files = FileSet::new();
+    con_files.owned.extend(["src".into(), "build.rs".into()]);
 
     // Generate Cargo.toml
-    let con_cargo = prepare_consumer_cargo_file(&mod_info)?;
+    let con_cargo = prepare_consumer_cargo_file(mod_info)?;
     con_files.files.insert("Cargo.toml".into(), con_cargo);
 
     // Add lib.rs and spec.rs
     con_files.files.insert("src/lib.rs".into(), con_formatted);
 
+    // Mirror the module tree
+    let mut out_dir_modules = HashMap::new();
+    for submodule in submodules {
+        if build_script {
+            // included from `OUT_DIR`, so relative paths can't work there
+            let dir = submodule.rel_path.parent().unwrap_or(Utf8Path::new(""));
+            let mut con_attrs = submodule.con_attrs.clone();
+            prefix_includes(
+                &mut con_attrs,
+                &format!("{}/", mod_info.mod_path.join("src").join(dir)),
+            );
+            out_dir_modules.insert(
+                submodule.module_path.clone(),
+                (con_attrs, submodule.con_items.clone()),
+            );
+        }
+
+        let mut con_attrs = submodule.con_attrs;
+        rebase_includes(&mut con_attrs, &submodule.rel_path, mod_dir);
+        let con_ast = syn::File {
+            shebang: None,
+            attrs: con_attrs,
+            items: submodule.con_items,
+        };
+        con_files.files.insert(
+            Utf8Path::new("src").join(&submodule.rel_path),
+            render_con(&submodule.source, &con_ast, &submodule.rel_path),
+        );
+    }
+
     con_files
         .files
         .insert(format!("src/{SPEC_PATH}").into(), spec_formatted);
-    let load_src = include_str!("templates/load.rs.template");
+    let load_src = match &proxy_src {
+        Some(proxy_src) => format!(
+            "{autogen_prefix}\n{}\n{proxy_src}",
+            include_str!("templates/load_isolated.rs.template")
+        ),
+        None => format!(
+            "{autogen_prefix}\n{}",
+            include_str!("templates/load.rs.template")
+        ),
+    };
     con_files
         .files
-        .insert(format!("src/{SUPPORT_PATH}").into(), load_src.to_string());
-
-    // Update mod files if different
-    let mod_path = Utf8Path::new(&mod_info.mod_path);
-    if mod_files.is_different(mod_path)? {
-        tracing::info!("📝 Changes detected in mod files for {}", mod_info.name);
-        mod_files.commit(mod_path)?;
-    }
+        .insert(format!("src/{SUPPORT_PATH}").into(), load_src);
+
+    // Consumers generated by their build script get all of that as a single file in
+    // `OUT_DIR`, and stubs to include it
+    let mut out_dir_files = None;
+    if build_script {
+        let mut items = con_ast.items.clone();
+        inline_mod_files(&mut items, &[], &out_dir_modules);
+        let lib_rs = prettyplease::unparse(&syn::File {
+            shebang: None,
+            attrs: Default::default(),
+            items,
+        });
 
-    // Update consumer files if different
-    let con_path = Utf8Path::new(&mod_info.con_path);
-    if con_files.is_different(con_path)? {
-        tracing::info!(
-            "📝 Changes detected in consumer files for {}",
-            mod_info.name
+        let mut files = FileSet::new();
+        files.files.insert(
+            "lib.rs".into(),
+            format!("{GENERATED_HEADER}, from the build script\n{lib_rs}"),
         );
-        con_files.commit(con_path)?;
-
-        // Verify compilation
-        tracing::info!("🔨 Running cargo check for {}", mod_info.name);
-        let start = std::time::Instant::now();
-        let status = std::process::Command::new("cargo")
-            .arg("check")
-            .arg("--package")
-            .arg(&mod_info.name)
-            .current_dir(workspace_root)
-            .status()?;
-
-        let duration = start.elapsed();
-        if status.success() {
-            tracing::info!("✅ Check passed in {:.2}s", duration.as_secs_f32());
-        } else {
-            tracing::error!(
-                "❌ Check failed for {} in {:.2}s",
-                mod_info.name,
-                duration.as_secs_f32()
-            );
-            if force {
-                tracing::warn!("⚠️ Continuing despite failed cargo check due to --force");
-            } else {
-                tracing::error!("⛔ Exiting due to failed cargo check");
-                std::process::exit(1);
+        for path in [SPEC_PATH, SUPPORT_PATH] {
+            if let Some(contents) = con_files
+                .files
+                .remove(Utf8Path::new(&format!("src/{path}")))
+            {
+                files.files.insert(path.into(), contents);
             }
         }
+        out_dir_files = Some(files);
+
+        con_files.files.retain(|path, _| !path.starts_with("src"));
+        let stub = prettyplease::unparse(&syn::File {
+            shebang: None,
+            attrs: con_ast.attrs.clone(),
+            items: vec![syn::parse_quote! {
+                include!(concat!(env!("OUT_DIR"), "/dylo/lib.rs"));
+            }],
+        });
+        con_files
+            .files
+            .insert("src/lib.rs".into(), format!("{con_prefix}{stub}"));
+        let build_rs = include_str!("templates/build.rs.template").replace("{mod_dir}", mod_dir);
+        con_files
+            .files
+            .insert("build.rs".into(), format!("{autogen_prefix}\n{build_rs}"));
+        source_map = SourceMap::default();
     }
-    Ok(())
+
+    Ok(Generated {
+        mod_files,
+        con_files,
+        source_map,
+        out_dir_files,
+    })
+}
+
+/// Declares the traits exported from a module other than the root as a macro,
+/// which that module invokes: `spec.rs` is included at the crate root, but the
+/// traits must live at their proper paths.
+fn spec_macro(spec: &ModuleSpec) -> String {
+    let name = spec.macro_name();
+    let body = prettyplease::unparse(&syn::File {
+        shebang: None,
+        attrs: Default::default(),
+        items: spec.items.clone(),
+    });
+    let body = body
+        .lines()
+        .map(|line| {
+            if line.is_empty() {
+                String::new()
+            } else {
+                format!("        {line}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(
+        "\n/// Traits exported from `crate::{path}`, which invokes this macro.\n#[doc(hidden)]\nmacro_rules! {name} {{\n    () => {{\n{body}\n    }};\n}}\npub(crate) use {name};\n",
+        path = spec.module_path.join("::"),
+    )
 }
 
-fn item_attributes(item: &mut Item) -> Option<&mut Vec<Attribute>> {
+pub(crate) fn item_attributes(item: &mut Item) -> Option<&mut Vec<Attribute>> {
     match item {
         Item::Const(item) => Some(&mut item.attrs),
         Item::Enum(item) => Some(&mut item.attrs),
@@ -286,221 +507,364 @@ fn item_attributes(item: &mut Item) -> Option<&mut Vec<Attribute>> {
     }
 }
 
-// recognizes `#[cfg(feature = "impl"]`
-fn is_cfg_feature_impl(attr: &Attribute) -> bool {
-    if !attr.path().is_ident("cfg") {
-        return false;
+/// Points relative paths of `#[doc = include_str!("...")]` attributes (as found in the
+/// mod's file at `src/{rel_path}`) into the mod crate, in directory `mod_dir` next to the
+/// consumer, so that consumers keep docs like `#![doc = include_str!("../README.md")]`.
+pub(crate) fn rebase_includes(attrs: &mut [Attribute], rel_path: &Utf8Path, mod_dir: &str) {
+    let dir = rel_path.parent().unwrap_or(Utf8Path::new(""));
+    let mut prefix = format!(
+        "{}{mod_dir}/src/",
+        "../".repeat(dir.components().count() + 2)
+    );
+    if !dir.as_str().is_empty() {
+        prefix.push_str(&format!("{dir}/"));
     }
-
-    let mut has_feature_impl = false;
-    let _ = attr.parse_nested_meta(|meta| {
-        if meta.path.is_ident("feature") {
-            let content = meta.input.to_string();
-            if content == "= \"impl\"" {
-                has_feature_impl = true;
-            }
-        }
-        Ok(())
-    });
-    has_feature_impl
+    prefix_includes(attrs, &prefix);
 }
 
-// recognizes `#[cfg(not(feature = "impl"))]`
-fn is_cfg_not_feature_impl(attr: &Attribute) -> bool {
-    if !attr.path().is_ident("cfg") {
-        return false;
-    }
-
-    let mut has_not = false;
-    let mut has_feature_impl = false;
-    let _ = attr.parse_nested_meta(|meta| {
-        if meta.path.is_ident("not") {
-            let _ = meta.parse_nested_meta(|meta| {
-                if meta.path.is_ident("feature") {
-                    let content = meta.input.to_string();
-                    if content == "= \"impl\"" {
-                        has_feature_impl = true;
-                    }
-                }
-                Ok(())
-            });
-            has_not = true;
+/// Prepends `prefix` to relative paths of `#[doc = include_str!("...")]` attributes.
+fn prefix_includes(attrs: &mut [Attribute], prefix: &str) {
+    for attr in attrs {
+        let syn::Meta::NameValue(nv) = &mut attr.meta else {
+            continue;
+        };
+        let syn::Expr::Macro(expr) = &mut nv.value else {
+            continue;
+        };
+        if !nv.path.is_ident("doc") || !expr.mac.path.is_ident("include_str") {
+            continue;
+        }
+        let Ok(lit) = expr.mac.parse_body::<syn::LitStr>() else {
+            continue;
+        };
+        let path = lit.value();
+        if Utf8Path::new(&path).is_relative() {
+            let rebased = syn::LitStr::new(&format!("{prefix}{path}"), lit.span());
+            expr.mac.tokens = rebased.into_token_stream();
         }
-        Ok(())
-    });
-    has_not && has_feature_impl
-}
-
-fn is_cfg_test(attr: &Attribute) -> bool {
-    if !attr.path().is_ident("cfg") {
-        return false;
     }
+}
 
-    let mut has_test = false;
-    let _ = attr.parse_nested_meta(|meta| {
-        if meta.path.is_ident("test") {
-            has_test = true;
-        }
-        Ok(())
-    });
-    has_test
+/// Traits exported from one module of a mod crate.
+pub(crate) struct ModuleSpec {
+    /// Module path from the crate root, e.g. `["net", "http"]` (empty for the root)
+    pub module_path: Vec<String>,
+    pub items: Vec<Item>,
 }
 
-fn should_remove_item(item: &mut Item) -> bool {
-    if let Some(attrs) = item_attributes(item) {
-        for attr in attrs {
-            if is_cfg_feature_impl(attr) || is_cfg_test(attr) {
-                return true;
-            }
+impl ModuleSpec {
+    /// Name of the macro `spec.rs` declares for this module's traits: `spec.rs` is
+    /// included at the crate root, so traits of other modules are declared by
+    /// having those modules invoke it.
+    ///
+    /// Each segment is prefixed with its length (`a::b` is `__dylo_spec_1a_1b`), since
+    /// any separator could also appear in identifiers: `a::b` and `a__b` mustn't collide.
+    pub fn macro_name(&self) -> String {
+        let mut name = "__dylo_spec".to_string();
+        for segment in &self.module_path {
+            let _ = write!(name, "_{}{segment}", segment.len());
         }
+        name
     }
-    false
-}
 
-enum InterfaceType {
-    NonSync,
-    Sync,
-}
+    pub fn is_root(&self) -> bool {
+        self.module_path.is_empty()
+    }
 
-impl InterfaceType {
-    // see definitions of `waxpoetic` itself
-    fn supertraits(&self) -> syn::punctuated::Punctuated<syn::TypeParamBound, syn::Token![+]> {
-        let mut p = syn::punctuated::Punctuated::new();
-        match self {
-            InterfaceType::NonSync => {
-                p.push(syn::parse_quote!(Send));
-                p.push(syn::parse_quote!('static));
-            }
-            InterfaceType::Sync => {
-                p.push(syn::parse_quote!(Send));
-                p.push(syn::parse_quote!(Sync));
-                p.push(syn::parse_quote!('static));
-            }
+    /// Whether `items` (the contents of the module) already invoke [`Self::macro_name`]
+    pub fn is_invoked_in(&self, items: &[Item]) -> bool {
+        let name = self.macro_name();
+        items.iter().any(|item| match item {
+            Item::Macro(mac) => mac
+                .mac
+                .path
+                .segments
+                .last()
+                .is_some_and(|segment| segment.ident == name),
+            _ => false,
+        })
+    }
+
+    pub fn invocation(&self) -> Item {
+        let name = syn::Ident::new(&self.macro_name(), proc_macro2::Span::call_site());
+        syn::parse_quote! {
+            crate::#name!();
         }
-        p
     }
 }
 
-pub(crate) fn transform_ast(items: &mut Vec<Item>, added_items: &mut Vec<Item>) {
+/// Strips impl-only items from `items` (the contents of the module at `module_path`),
+/// recursing into inline modules, and turns `#[dylo::export]` impls into traits,
+/// collected in `specs`. Modules other than the root get an invocation of their
+/// spec macro, see [`ModuleSpec::macro_name`].
+pub(crate) fn transform_ast(
+    items: &mut Vec<Item>,
+    module_path: &[String],
+    specs: &mut Vec<ModuleSpec>,
+) {
+    let mut added_items = Vec::new();
     items.retain_mut(|item| {
         let mut keep = true;
 
         match item {
             Item::Impl(imp) => {
                 for attr in &imp.attrs {
-                    if attr.path().segments.len() == 2
-                        && attr.path().segments[0].ident == "dylo"
-                        && attr.path().segments[1].ident == "export"
-                    {
-                        let iface_typ = if let Ok(_meta) = attr.meta.require_path_only() {
-                            Some(InterfaceType::Sync)
-                        } else if let Ok(list) = attr.meta.require_list() {
-                            if list.tokens.to_string().contains("nonsync") {
-                                Some(InterfaceType::NonSync)
-                            } else {
-                                None
-                            }
-                        } else {
-                            None
-                        };
-
-                        if let Some(iface_typ) = iface_typ {
+                    if is_dylo_export(attr) {
+                        // invalid arguments are reported by `lint_exports`
+                        if let Ok(args) = ExportArgs::from_attr(attr) {
                             let tokens = (&imp).into_token_stream();
-                            added_items.push(declare_trait(&tokens, &iface_typ)[0].clone());
+                            let declared = declare_trait(&tokens, &args);
+                            let interface_item = match &declared[..] {
+                                [Item::Trait(trait_item), Item::Type(alias)]
+                                    if trait_item.ident == "Mod" =>
+                                {
+                                    Some(declare_interface(trait_item, alias))
+                                }
+                                _ => None,
+                            };
+                            added_items.extend(declared);
+                            added_items.extend(interface_item);
+                            if let Some((_, trait_path, _)) = &imp.trait_ {
+                                let trait_ident = args
+                                    .name
+                                    .as_ref()
+                                    .unwrap_or(&trait_path.segments.last().unwrap().ident);
+                                added_items.push(declare_hash(imp, attr, trait_ident));
+                            }
                         }
                         keep = false
                     }
                 }
             }
-            Item::Enum(enm) => {
-                filter_out_cfg_attr_impl(&mut enm.attrs);
-            }
-            Item::Struct(stru) => {
-                filter_out_cfg_attr_impl(&mut stru.attrs);
-                for f in stru.fields.iter_mut() {
-                    filter_out_cfg_attr_impl(&mut f.attrs);
-                }
-            }
             _ => {
                 // ignore
             }
         }
 
-        if should_remove_item(item) {
-            keep = false
-        } else {
-            // remove any cfg(not(feature = "impl")) attributes
-            if let Some(attrs) = item_attributes(item) {
-                attrs.retain(|attr| !is_cfg_not_feature_impl(attr));
+        if let Some(attrs) = item_attributes(item) {
+            if !eval_cfg_attrs(attrs) {
+                keep = false
             }
         }
+        if keep {
+            strip_members(item);
+        }
 
         keep
     });
-}
 
-fn filter_out_cfg_attr_impl(attrs: &mut Vec<Attribute>) {
-    attrs.retain_mut(|attr| {
-        if let syn::Meta::List(list) = &attr.meta {
-            if let Some(path_segment) = list.path.segments.first() {
-                if path_segment.ident == "cfg_attr" {
-                    if let Some(nested) = list.tokens.to_string().split(",").next() {
-                        if nested.contains("feature") && nested.contains("\"impl\"") {
-                            return false;
-                        }
-                    }
-                }
+    if !added_items.is_empty() {
+        let spec = ModuleSpec {
+            module_path: module_path.to_vec(),
+            items: added_items,
+        };
+        if !spec.is_root() && !spec.is_invoked_in(items) {
+            items.push(spec.invocation());
+        }
+        specs.push(spec);
+    }
+
+    for item in items.iter_mut() {
+        if let Item::Mod(item_mod) = item {
+            if let Some((_, content)) = &mut item_mod.content {
+                let module_path = [module_path, &[item_mod.ident.unraw().to_string()]].concat();
+                transform_ast(content, &module_path, specs);
             }
         }
-        true
-    })
+    }
 }
 
-fn declare_trait(tokens: &proc_macro2::TokenStream, iface_typ: &InterfaceType) -> Vec<Item> {
+fn declare_trait(tokens: &proc_macro2::TokenStream, args: &ExportArgs) -> Vec<Item> {
     let mut added_items = Vec::new();
     let file = syn::parse2::<syn::File>(tokens.clone()).unwrap();
     for item in &file.items {
         if let Item::Impl(imp) = item {
             if let Some((_, trait_path, _)) = &imp.trait_ {
-                let mut trait_methods = Vec::new();
+                let mut trait_items = Vec::new();
+                let mut assoc_types = Vec::new();
 
                 for item in &imp.items {
+                    if let ImplItem::Type(type_item) = item {
+                        let ident = &type_item.ident;
+                        trait_items.push(syn::TraitItem::Type(syn::TraitItemType {
+                            attrs: forwarded_attrs(&type_item.attrs, &args.forward),
+                            type_token: type_item.type_token,
+                            ident: ident.clone(),
+                            generics: type_item.generics.clone(),
+                            colon_token: None,
+                            bounds: Default::default(),
+                            default: None,
+                            semi_token: type_item.semi_token,
+                        }));
+                        let ty = &type_item.ty;
+                        assoc_types.push(quote::quote! { #ident = #ty });
+                    }
                     if let ImplItem::Fn(fn_item) = item {
+                        let sig = if fn_item.sig.asyncness.is_some() {
+                            let send = args.interface.send_futures();
+                            desugar_async_signature(&fn_item.sig, &imp.generics, send)
+                        } else {
+                            fn_item.sig.clone()
+                        };
+                        // `#[dylo::provided]` methods become default methods, bodies included
+                        let provided = fn_item.attrs.iter().any(is_dylo_provided);
                         let trait_fn = syn::TraitItemFn {
-                            attrs: fn_item.attrs.clone(),
-                            sig: remove_mutable_bindings_from_sig(&fn_item.sig),
-                            default: None,
+                            attrs: forwarded_attrs(&fn_item.attrs, &args.forward),
+                            sig: if provided {
+                                sig
+                            } else {
+                                remove_mutable_bindings_from_sig(&sig)
+                            },
+                            default: provided.then(|| fn_item.block.clone()),
                             semi_token: None,
                         };
-                        trait_methods.push(trait_fn);
+                        trait_items.push(syn::TraitItem::Fn(trait_fn));
                     }
                 }
 
+                let trait_ident = args
+                    .name
+                    .as_ref()
+                    .unwrap_or(&trait_path.segments.last().unwrap().ident);
+                let vis = args
+                    .vis
+                    .clone()
+                    .unwrap_or(syn::Visibility::Public(syn::token::Pub::default()));
                 let trait_item = Item::Trait(syn::ItemTrait {
-                    attrs: Vec::new(),
-                    vis: syn::Visibility::Public(syn::token::Pub::default()),
-                    unsafety: None,
+                    attrs: forwarded_attrs(&imp.attrs, &args.forward),
+                    vis: vis.clone(),
+                    unsafety: args.unsafety.then(syn::token::Unsafe::default),
                     auto_token: None,
                     restriction: None,
                     trait_token: syn::token::Trait::default(),
-                    ident: trait_path.segments.last().unwrap().ident.clone(),
+                    ident: trait_ident.clone(),
                     generics: imp.generics.clone(),
                     colon_token: None,
-                    supertraits: iface_typ.supertraits(),
+                    supertraits: args.interface.supertraits(&args.supertraits),
                     brace_token: syn::token::Brace::default(),
-                    items: trait_methods.into_iter().map(syn::TraitItem::Fn).collect(),
+                    items: trait_items,
                 });
-
                 added_items.push(trait_item);
+
+                let trait_args = imp.generics.params.iter().map(|param| match param {
+                    syn::GenericParam::Lifetime(param) => param.lifetime.to_token_stream(),
+                    syn::GenericParam::Type(param) => param.ident.to_token_stream(),
+                    syn::GenericParam::Const(param) => param.ident.to_token_stream(),
+                });
+                let trait_args: Vec<_> = trait_args.chain(assoc_types.iter().cloned()).collect();
+                let bound: syn::Path = syn::parse_quote!(#trait_ident<#(#trait_args),*>);
+
+                // `dyn Trait` must name its associated types: consumers (and the loader,
+                // for `Mod`) go through an alias that does
+                if trait_ident == "Mod" || !assoc_types.is_empty() {
+                    let alias_ident = quote::format_ident!("Dyn{trait_ident}");
+                    let params = unbounded_params(&imp.generics);
+                    added_items.push(syn::parse_quote! {
+                        #vis type #alias_ident<#params> = dyn #bound;
+                    });
+                }
+
+                for instantiation in &args.instantiations {
+                    added_items.push(instantiate::check(instantiation, &imp.generics, &bound));
+                }
             }
         }
     }
     added_items
 }
 
+/// Attributes of an exported impl (or of its members) that carry over to the generated
+/// trait: docs, deprecation, `#[must_use]`, lint levels and `cfg`, plus the ones listed in
+/// `#[dylo::export(forward(...))]`. Others (`#[inline]`, `#[tracing::instrument]`...) only
+/// make sense on the impl, and are left there.
+fn forwarded_attrs(attrs: &[Attribute], forward: &[syn::Path]) -> Vec<Attribute> {
+    const FORWARDED: &[&str] = &[
+        "doc",
+        "deprecated",
+        "must_use",
+        "allow",
+        "warn",
+        "deny",
+        "forbid",
+        "expect",
+        "cfg",
+    ];
+    let forward: Vec<String> = forward
+        .iter()
+        .map(|path| path.to_token_stream().to_string())
+        .collect();
+    let is_forwarded = |path: &syn::Path| {
+        FORWARDED.iter().any(|name| path.is_ident(name))
+            || forward.contains(&path.to_token_stream().to_string())
+    };
+
+    attrs
+        .iter()
+        .filter_map(|attr| {
+            if !attr.path().is_ident("cfg_attr") {
+                return is_forwarded(attr.path()).then(|| attr.clone());
+            }
+
+            // keep `cfg_attr` for whatever it would expand to that's forwarded
+            let list = attr.meta.require_list().ok()?;
+            let mut nested = list
+                .parse_args_with(
+                    syn::punctuated::Punctuated::<syn::Meta, syn::Token![,]>::parse_terminated,
+                )
+                .ok()?
+                .into_iter();
+            let predicate = nested.next()?;
+            let kept: Vec<syn::Meta> = nested.filter(|meta| is_forwarded(meta.path())).collect();
+            if kept.is_empty() {
+                return None;
+            }
+            let mut attr = attr.clone();
+            attr.meta = syn::parse_quote!(cfg_attr(#predicate, #(#kept),*));
+            Some(attr)
+        })
+        .collect()
+}
+
+/// Declares `DynMod` as something dylo-runtime can load, with an identity
+/// derived from the trait definition and its associated types: if the consumer
+/// and the module disagree on it, the loader refuses to transmute one into the other.
+fn declare_interface(trait_item: &syn::ItemTrait, alias: &syn::ItemType) -> Item {
+    let ident = &trait_item.ident;
+    let alias_ident = &alias.ident;
+
+    // docs don't change the ABI, editing them shouldn't make the loader refuse the mod
+    let mut undocumented = trait_item.clone();
+    undocumented
+        .attrs
+        .retain(|attr| !attr.path().is_ident("doc"));
+    for item in &mut undocumented.items {
+        match item {
+            syn::TraitItem::Fn(f) => f.attrs.retain(|attr| !attr.path().is_ident("doc")),
+            syn::TraitItem::Type(t) => t.attrs.retain(|attr| !attr.path().is_ident("doc")),
+            _ => {}
+        }
+    }
+    let definition = format!(
+        "{} {}",
+        undocumented.to_token_stream(),
+        alias.to_token_stream()
+    );
+    let hash = fnv1a(definition.as_bytes());
+    let id = syn::LitCStr::new(
+        &std::ffi::CString::new(format!("{ident}:{hash:016x}")).unwrap(),
+        proc_macro2::Span::call_site(),
+    );
+
+    syn::parse_quote! {
+        unsafe impl ::dylo_runtime::details::Interface for #alias_ident {
+            const ID: &'static ::std::ffi::CStr = #id;
+        }
+    }
+}
+
 fn remove_mutable_bindings_from_sig(sig: &syn::Signature) -> syn::Signature {
     let mut newsig = sig.clone();
-    for input in &mut newsig.inputs {
+    for (i, input) in newsig.inputs.iter_mut().enumerate() {
         match input {
             syn::FnArg::Receiver(receiver) => {
                 if matches!(receiver.ty.as_ref(), Type::Reference(_)) {
@@ -518,6 +882,10 @@ fn remove_mutable_bindings_from_sig(sig: &syn::Signature) -> syn::Signature {
                     } else {
                         pat_ident.mutability = None;
                     }
+                } else if !matches!(&*pat_type.pat, syn::Pat::Wild(_)) {
+                    // patterns like `(a, b): (u32, u32)` aren't allowed without a body
+                    let ident = quote::format_ident!("arg{i}");
+                    *pat_type.pat = syn::parse_quote!(#ident);
                 }
             }
         }
@@ -526,29 +894,109 @@ fn remove_mutable_bindings_from_sig(sig: &syn::Signature) -> syn::Signature {
     newsig
 }
 
+/// Makes sure `dylo-runtime` is a dependency at [`DYLO_RUNTIME_VERSION`], with the
+/// `isolation` feature if needed, preserving any other settings (like `features`).
+/// Returns true if anything changed.
+fn update_dylo_runtime_dependency(deps_table: &mut toml_edit::Table, isolation: bool) -> bool {
+    let Some(dep) = deps_table.get_mut("dylo-runtime") else {
+        let dep = if isolation {
+            let mut table = toml_edit::InlineTable::new();
+            table.insert("version", DYLO_RUNTIME_VERSION.into());
+            table.insert(
+                "features",
+                toml_edit::Array::from_iter(["isolation"]).into(),
+            );
+            toml_edit::value(table)
+        } else {
+            toml_edit::value(DYLO_RUNTIME_VERSION)
+        };
+        deps_table.insert("dylo-runtime", dep);
+        return true;
+    };
+
+    if !isolation {
+        if dep.as_str() == Some(DYLO_RUNTIME_VERSION) {
+            return false;
+        }
+        if let Some(table) = dep.as_table_like_mut() {
+            if table.get("version").and_then(|v| v.as_str()) == Some(DYLO_RUNTIME_VERSION) {
+                return false;
+            }
+            table.insert("version", toml_edit::value(DYLO_RUNTIME_VERSION));
+            return true;
+        }
+        *dep = toml_edit::value(DYLO_RUNTIME_VERSION);
+        return true;
+    }
+
+    if dep.is_str() {
+        *dep = toml_edit::value(toml_edit::InlineTable::new());
+    }
+    let Some(table) = dep.as_table_like_mut() else {
+        return false;
+    };
+
+    let mut changed = false;
+    if table.get("version").and_then(|v| v.as_str()) != Some(DYLO_RUNTIME_VERSION) {
+        table.insert("version", toml_edit::value(DYLO_RUNTIME_VERSION));
+        changed = true;
+    }
+    let has_isolation = table
+        .get("features")
+        .and_then(|f| f.as_array())
+        .is_some_and(|f| f.iter().any(|v| v.as_str() == Some("isolation")));
+    if !has_isolation {
+        let mut features = table
+            .get("features")
+            .and_then(|f| f.as_array())
+            .cloned()
+            .unwrap_or_default();
+        features.push("isolation");
+        table.insert("features", toml_edit::value(features));
+        changed = true;
+    }
+    changed
+}
+
+/// Parses the mod's `Cargo.toml` into an editable document.
+fn read_manifest(mod_info: &ModInfo) -> Result<toml_edit::DocumentMut, Error> {
+    let path = mod_info.mod_path.join("Cargo.toml");
+    let mod_cargo = fs_err::read_to_string(&path)?;
+    mod_cargo
+        .parse::<toml_edit::DocumentMut>()
+        .map_err(|e| Error::Manifest {
+            path,
+            message: e.to_string(),
+        })
+}
+
 /// When generating the consumer manifest from a mod manifest:
 /// - Changes package name to strip the "mod-" prefix
 /// - Removes the dev-dependencies section
 /// - Removes the dylo dependency
 /// - Removes the "impl" feature & any dependencies it enables
-pub fn prepare_consumer_cargo_file(mod_info: &ModInfo) -> std::io::Result<String> {
+pub fn prepare_consumer_cargo_file(mod_info: &ModInfo) -> Result<String, Error> {
+    let manifest_error = |message: &str| Error::Manifest {
+        path: mod_info.mod_path.join("Cargo.toml"),
+        message: message.to_string(),
+    };
+
     // Parse the TOML doc into an editable format
-    let mod_cargo = fs_err::read_to_string(mod_info.mod_path.join("Cargo.toml"))?;
-    let mut doc = mod_cargo.parse::<toml_edit::DocumentMut>().unwrap();
+    let mut doc = read_manifest(mod_info)?;
+    let isolation = isolation_enabled(&doc);
 
     // Update package name to strip the "mod-" prefix
     doc["package"]["name"] = toml_edit::value(mod_info.name.clone());
 
     // Update crate-type from cdylib to rlib
-    let crate_type = doc["lib"]["crate-type"]
-        .as_array()
-        .expect("lib.crate-type must be an array");
-
-    assert_eq!(
-        crate_type.iter().next().unwrap().as_str().unwrap(),
-        "cdylib",
-        "lib.crate-type must be [\"cdylib\"]"
-    );
+    let crate_type = doc
+        .get("lib")
+        .and_then(|lib| lib.get("crate-type"))
+        .and_then(|crate_type| crate_type.as_array())
+        .ok_or_else(|| manifest_error("lib.crate-type must be an array"))?;
+    if crate_type.iter().map(|v| v.as_str()).collect::<Vec<_>>() != [Some("cdylib")] {
+        return Err(manifest_error("lib.crate-type must be [\"cdylib\"]"));
+    }
     doc["lib"]["crate-type"] = toml_edit::value(toml_edit::Array::from_iter(["rlib"]));
 
     doc["package"]["description"] = toml_edit::value(format!(
@@ -556,17 +1004,16 @@ pub fn prepare_consumer_cargo_file(mod_info: &ModInfo) -> std::io::Result<String
         mod_info.name
     ));
 
-    let features_enabled_by_impl_feature: Vec<String> = doc
-        .get("features")
-        .and_then(|f| f.get("impl"))
-        .map(|i| {
-            i.as_array()
-                .unwrap()
+    let features_enabled_by_impl_feature: Vec<String> =
+        match doc.get("features").and_then(|f| f.get("impl")) {
+            Some(features) => features
+                .as_array()
+                .ok_or_else(|| manifest_error("features.impl must be an array"))?
                 .iter()
-                .map(|v| v.as_str().unwrap().to_string())
-                .collect()
-        })
-        .unwrap_or_default();
+                .filter_map(|v| v.as_str().map(str::to_string))
+                .collect(),
+            None => Vec::new(),
+        };
 
     let mut features_enabled_by_other_features: HashSet<String> = Default::default();
     if let Some(features) = doc.get("features").and_then(|f| f.as_table()) {
@@ -606,9 +1053,9 @@ pub fn prepare_consumer_cargo_file(mod_info: &ModInfo) -> std::io::Result<String
         if let Some(default) = features.get_mut("default") {
             let array = default
                 .as_array()
-                .unwrap()
+                .ok_or_else(|| manifest_error("features.default must be an array"))?
                 .iter()
-                .filter(|v| v.as_str().unwrap() != "impl")
+                .filter(|v| v.as_str() != Some("impl"))
                 .collect::<Vec<_>>();
             *default = toml_edit::value(toml_edit::Array::from_iter(array));
         }
@@ -616,7 +1063,10 @@ pub fn prepare_consumer_cargo_file(mod_info: &ModInfo) -> std::io::Result<String
 
     // Now remove the impl feature altogether
     if let Some(features) = doc.get_mut("features") {
-        features.as_table_mut().unwrap().remove("impl");
+        features
+            .as_table_like_mut()
+            .ok_or_else(|| manifest_error("features must be a table"))?
+            .remove("impl");
     }
 
     // Remove dev-dependencies section if it exists
@@ -624,15 +1074,29 @@ pub fn prepare_consumer_cargo_file(mod_info: &ModInfo) -> std::io::Result<String
         doc.remove("dev-dependencies");
     }
 
+    // Consumers generated by their build script need dylo-cli to run it
+    if build_script_enabled(&doc) {
+        if let Some(package) = doc["package"].as_table_mut() {
+            package.remove("build");
+        }
+        let build_deps = doc
+            .entry("build-dependencies")
+            .or_insert_with(toml_edit::table);
+        // only the library: not the `dylo` binary, and what it needs
+        let mut dep = toml_edit::InlineTable::new();
+        dep.insert("version", env!("CARGO_PKG_VERSION").into());
+        dep.insert("default-features", false.into());
+        build_deps["dylo-cli"] = toml_edit::value(dep);
+    }
+
     // Remove dylo dependency if it exists
     if let Some(deps) = doc.get_mut("dependencies") {
-        if deps.is_table() {
-            let deps_table = deps.as_table_mut().unwrap();
+        if let Some(deps_table) = deps.as_table_mut() {
             // Remove dylo from dependencies
             deps_table.remove("dylo");
 
             // Add dylo-runtime as a dependency
-            deps_table.insert("dylo-runtime", DYLO_RUNTIME_VERSION.into());
+            update_dylo_runtime_dependency(deps_table, isolation);
 
             // Remove impl_specific_deps from dependencies
             let mut removed_deps = Vec::new();
@@ -1,16 +1,62 @@
+use camino::Utf8Path;
+
 use crate::{
-    codegen::codegen_mod,
+    codegen::Generator,
     dependency::{add_dependency, remove_dependency},
-    types::{DyloCommand, Scope},
+    diagnostics::SourceMap,
+    error::Error,
+    types::{CheckOptions, DyloCommand, ModInfo, ProcessReason, Scope},
+    verify::verify,
     workspace::{get_single_mod, list_mods},
 };
 
 pub fn run_command(workspace_root: camino::Utf8PathBuf, command: DyloCommand) -> eyre::Result<()> {
     match command {
-        DyloCommand::Default { scope, force } => {
+        DyloCommand::Default {
+            scope,
+            force,
+            check,
+        } => {
+            let mut regenerated = Vec::new();
+            let mut source_map = SourceMap::default();
             for mod_info in list_mods(&workspace_root, scope)? {
-                codegen_mod(&workspace_root, mod_info, force)?;
+                let name = mod_info.name.clone();
+                if let Some(mod_source_map) = codegen_mod(&workspace_root, mod_info, force)? {
+                    regenerated.push(name);
+                    source_map.extend(mod_source_map);
+                }
+            }
+
+            // Everything that changed is checked at once, once everything is generated
+            if check.enabled
+                && !regenerated.is_empty()
+                && !verify(&workspace_root, &regenerated, &check, &source_map)?
+            {
+                eyre::bail!("Check failed for {}", regenerated.join(", "));
+            }
+        }
+        DyloCommand::Check { scope } => {
+            let generator = Generator::new(&workspace_root);
+            let mut out_of_date = Vec::new();
+            for mod_info in generator.mods(scope)? {
+                let paths = generator.generate(&mod_info)?.out_of_date(&mod_info)?;
+                if paths.is_empty() {
+                    continue;
+                }
+                eprintln!("{} is out of date:", mod_info.name);
+                for path in paths {
+                    eprintln!("  {}", path.strip_prefix(&workspace_root).unwrap_or(&path));
+                }
+                out_of_date.push(mod_info.name);
             }
+
+            if !out_of_date.is_empty() {
+                eyre::bail!(
+                    "Out of date: {}, run `dylo gen` to regenerate",
+                    out_of_date.join(", ")
+                );
+            }
+            tracing::info!("✅ Everything is up to date");
         }
         DyloCommand::List { scope } => {
             let mods = list_mods(&workspace_root, scope)?;
@@ -56,8 +102,69 @@ pub fn run_command(workspace_root: camino::Utf8PathBuf, command: DyloCommand) ->
     Ok(())
 }
 
+/// Regenerates the consumer of a mod (and the mod's own generated files) if needed.
+/// Returns where the consumer's sources come from if it changed, so it can be checked
+/// (see [`crate::verify`]).
+pub fn codegen_mod(
+    workspace_root: &Utf8Path,
+    mod_info: ModInfo,
+    force: bool,
+) -> Result<Option<SourceMap>, Error> {
+    let mod_ts = mod_info
+        .mod_timestamp
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let con_ts = mod_info
+        .con_timestamp
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let diff = if mod_ts > con_ts {
+        format!("mod is newer by {} seconds", mod_ts - con_ts)
+    } else {
+        format!("con is newer by {} seconds", con_ts - mod_ts)
+    };
+
+    tracing::debug!(
+        "Mod '{name}' in {mod_path}, {con_path}\n  mod ts = {mod_ts}\n  con ts = {con_ts}\n  {diff}",
+        name = mod_info.name,
+        mod_path = mod_info.mod_path,
+        con_path = mod_info.con_path,
+        mod_ts = mod_ts,
+        con_ts = con_ts,
+        diff = diff
+    );
+
+    // When force is true, we regenerate regardless of timestamps
+    let reason = if force {
+        ProcessReason::Force
+    } else if !mod_info.con_path.exists() {
+        ProcessReason::Missing
+    } else if mod_info.mod_timestamp > mod_info.con_timestamp {
+        ProcessReason::Modified
+    } else {
+        // If force is false and none of the conditions above are true,
+        // no regeneration is needed
+        return Ok(None);
+    };
+
+    tracing::debug!("📦 Processing mod {} (because {:?})", mod_info.name, reason);
+
+    let generated = Generator::new(workspace_root).generate(&mod_info)?;
+    if generated.commit(&mod_info)? {
+        Ok(Some(generated.source_map))
+    } else {
+        Ok(None)
+    }
+}
+
 pub fn parse_args(ambient_scope: Scope) -> DyloCommand {
-    let cli = clap::Command::new("dylo")
+    parse_matches(&cli().get_matches(), ambient_scope)
+}
+
+fn cli() -> clap::Command {
+    clap::Command::new("dylo")
         .about("Dynamic loading utility for Rust")
         .subcommand_required(true)
         .subcommand(
@@ -70,6 +177,33 @@ pub fn parse_args(ambient_scope: Scope) -> DyloCommand {
                         .help("Force regeneration of all consumer crates")
                         .action(clap::ArgAction::SetTrue),
                 )
+                .arg(
+                    clap::Arg::new("check")
+                        .long("check")
+                        .help("Don't write anything, exit with an error if anything is out of date")
+                        .action(clap::ArgAction::SetTrue)
+                        .conflicts_with_all(["force", "no-check", "check-cmd", "check-impl"]),
+                )
+                .arg(
+                    clap::Arg::new("no-check")
+                        .long("no-check")
+                        .help("Don't check regenerated crates")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    clap::Arg::new("check-cmd")
+                        .long("check-cmd")
+                        .help("Check regenerated crates with this instead of `cargo check`")
+                        .value_name("CMD")
+                        .conflicts_with("no-check"),
+                )
+                .arg(
+                    clap::Arg::new("check-impl")
+                        .long("check-impl")
+                        .help("Also check regenerated mods, with their `impl` feature")
+                        .action(clap::ArgAction::SetTrue)
+                        .conflicts_with("no-check"),
+                )
                 .arg(
                     clap::Arg::new("mod")
                         .short('m')
@@ -136,10 +270,10 @@ pub fn parse_args(ambient_scope: Scope) -> DyloCommand {
                     .help("List all mods in the workspace")
                     .action(clap::ArgAction::SetTrue),
             ),
-        );
-
-    let matches = cli.get_matches();
+        )
+}
 
+fn parse_matches(matches: &clap::ArgMatches, ambient_scope: Scope) -> DyloCommand {
     fn get_module_scope(
         matches: &clap::ArgMatches,
         ambient_scope: &Scope,
@@ -183,7 +317,22 @@ pub fn parse_args(ambient_scope: Scope) -> DyloCommand {
             };
             tracing::debug!("Final scope determined: {scope:?}");
 
-            DyloCommand::Default { force, scope }
+            if sub_matches.get_flag("check") {
+                return DyloCommand::Check { scope };
+            }
+
+            let check = CheckOptions {
+                enabled: !sub_matches.get_flag("no-check"),
+                command: sub_matches.get_one::<String>("check-cmd").cloned(),
+                impl_features: sub_matches.get_flag("check-impl"),
+            };
+            tracing::debug!("Check options: {check:?}");
+
+            DyloCommand::Default {
+                force,
+                scope,
+                check,
+            }
         }
 
         Some(("add", sub_matches)) => {
@@ -232,3 +381,61 @@ pub fn parse_args(ambient_scope: Scope) -> DyloCommand {
         _ => unreachable!("clap ensures we have a valid subcommand"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gen_check_options(args: &[&str]) -> Result<CheckOptions, clap::Error> {
+        let matches = cli().try_get_matches_from(["dylo", "gen"].iter().chain(args))?;
+        match parse_matches(&matches, Scope::Workspace) {
+            DyloCommand::Default { check, .. } => Ok(check),
+            _ => panic!("`dylo gen {}` isn't a default run", args.join(" ")),
+        }
+    }
+
+    #[test]
+    fn parses_check_options() {
+        let check = gen_check_options(&[]).unwrap();
+        assert!(check.enabled && check.command.is_none() && !check.impl_features);
+
+        let check = gen_check_options(&["--no-check"]).unwrap();
+        assert!(!check.enabled);
+
+        let check =
+            gen_check_options(&["--check-cmd", "cargo clippy -- -D warnings", "--check-impl"])
+                .unwrap();
+        assert!(check.enabled && check.impl_features);
+        assert_eq!(
+            check.command.as_deref(),
+            Some("cargo clippy -- -D warnings")
+        );
+    }
+
+    #[test]
+    fn rejects_conflicting_check_options() {
+        for args in [
+            &["--no-check", "--check-cmd", "cargo clippy"][..],
+            &["--no-check", "--check-impl"],
+            &["--check", "--check-impl"],
+            &["--check", "--check-cmd", "cargo clippy"],
+            &["--check", "--no-check"],
+            &["--check", "--force"],
+        ] {
+            assert!(gen_check_options(args).is_err(), "{args:?}");
+        }
+    }
+
+    #[test]
+    fn check_only_compares() {
+        let matches = cli()
+            .try_get_matches_from(["dylo", "gen", "--check"])
+            .unwrap();
+        assert!(matches!(
+            parse_matches(&matches, Scope::Workspace),
+            DyloCommand::Check {
+                scope: Scope::Workspace
+            }
+        ));
+    }
+}
@@ -1,4 +1,4 @@
-use camino::{Utf8Path, Utf8PathBuf};
+use camino::Utf8Path;
 use std::process::Command as ProcessCommand;
 use toml_edit::{Array, DocumentMut, Item};
 
@@ -34,10 +34,9 @@ pub fn add_dependency(mod_path: &Utf8Path, deps: &[String], is_impl: bool) -> st
     let status = command.status()?;
 
     if !status.success() {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("Failed to add dependency: cargo add exited with status {status}"),
-        ));
+        return Err(std::io::Error::other(format!(
+            "Failed to add dependency: cargo add exited with status {status}"
+        )));
     }
 
     // If this is an impl-only dependency, update the impl feature and remove auto-generated feature
@@ -138,10 +137,9 @@ pub fn remove_dependency(mod_path: &Utf8Path, deps: &[String]) -> std::io::Resul
     let status = command.status()?;
 
     if !status.success() {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("Failed to remove dependency: cargo rm exited with status {status}"),
-        ));
+        return Err(std::io::Error::other(format!(
+            "Failed to remove dependency: cargo rm exited with status {status}"
+        )));
     }
 
     // Also need to remove from impl feature if it exists
@@ -190,28 +188,10 @@ pub fn remove_dependency(mod_path: &Utf8Path, deps: &[String]) -> std::io::Resul
     Ok(())
 }
 
-/// Find mod directory by name
-pub fn find_mod_by_name(workspace_root: &Utf8Path, mod_name: &str) -> Option<Utf8PathBuf> {
-    let mod_dir_name = format!("mod-{mod_name}");
-
-    // First check if there's a direct mod-foo directory in the workspace
-    let direct_path = workspace_root.join(&mod_dir_name);
-    if direct_path.exists() && direct_path.is_dir() {
-        return Some(direct_path);
-    }
-
-    // Then try to find it under crates/
-    let crates_path = workspace_root.join("crates").join(&mod_dir_name);
-    if crates_path.exists() && crates_path.is_dir() {
-        return Some(crates_path);
-    }
-
-    None
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use camino::Utf8PathBuf;
     use std::path::Path;
     use tempfile::tempdir;
 
@@ -0,0 +1,201 @@
+//! Maps the diagnostics of `cargo check` on consumer crates back to the mod sources they
+//! were generated from: generated files get overwritten, the mod is where fixes go.
+//!
+//! Consumer files are rendered from their mod's sources (see [`crate::render`]), which
+//! records the line each of their lines was copied from. `dylo gen` runs cargo with
+//! `--message-format=json`, and locations in the diagnostics rustc renders are rewritten
+//! with those line maps. Locations that can't be mapped (synthesized code, files that were
+//! formatted with prettyplease) are left as they are.
+
+use std::collections::HashMap;
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+/// For each line of a generated file, the line of the source it was copied from, if any.
+/// Lines are 1-based, like in diagnostics.
+#[derive(Default, Clone)]
+pub struct LineMap {
+    lines: Vec<Option<usize>>,
+}
+
+impl LineMap {
+    /// Records that `line` starts with something copied from `source_line`, unless
+    /// something was recorded for it already.
+    pub fn record(&mut self, line: usize, source_line: usize) {
+        let Some(index) = line.checked_sub(1) else {
+            return;
+        };
+        if self.lines.len() <= index {
+            self.lines.resize(index + 1, None);
+        }
+        self.lines[index].get_or_insert(source_line);
+    }
+
+    /// Moves every line down by `by` (up if negative), as when lines are added (or
+    /// removed) at the top of the generated file.
+    pub fn shift(&mut self, by: isize) {
+        if by >= 0 {
+            self.lines
+                .splice(0..0, std::iter::repeat_n(None, by.unsigned_abs()));
+        } else {
+            self.lines.drain(..by.unsigned_abs().min(self.lines.len()));
+        }
+    }
+
+    pub fn get(&self, line: usize) -> Option<usize> {
+        self.lines.get(line.checked_sub(1)?).copied().flatten()
+    }
+}
+
+/// Generated files, and the sources they come from, by path relative to the workspace
+/// root (which is how rustc refers to files of workspace members).
+#[derive(Default)]
+pub struct SourceMap {
+    files: HashMap<Utf8PathBuf, (Utf8PathBuf, LineMap)>,
+}
+
+impl SourceMap {
+    pub fn insert(&mut self, generated: Utf8PathBuf, source: Utf8PathBuf, lines: LineMap) {
+        self.files.insert(generated, (source, lines));
+    }
+
+    /// Adds the files of `other`, as when several mods are checked at once.
+    pub fn extend(&mut self, other: SourceMap) {
+        self.files.extend(other.files);
+    }
+
+    fn lookup(&self, path: &str, line: usize) -> Option<(&Utf8Path, usize)> {
+        let (source, lines) = self.files.get(Utf8Path::new(path))?;
+        Some((source, lines.get(line)?))
+    }
+
+    /// Rewrites a diagnostic as rendered by rustc, like:
+    ///
+    /// ```text
+    /// error[E0412]: cannot find type `Reqwest` in this scope
+    ///  --> foo/src/lib.rs:9:28
+    ///   |
+    /// 9 |     fn get(&self, client: Reqwest);
+    ///   |                           ^^^^^^^ not found in this scope
+    /// ```
+    ///
+    /// Each `-->` (or `:::`) location, and the line numbers of the snippet that follows,
+    /// are mapped if every one of them can be.
+    pub fn rewrite(&self, rendered: &str) -> String {
+        let lines: Vec<&str> = rendered.split('\n').collect();
+        // line numbers are padded to the same width for the whole diagnostic, and
+        // locations are indented by as much
+        let Some(width) = lines.iter().find_map(|line| {
+            let width = line.find("--> ")?;
+            line[..width].trim().is_empty().then_some(width)
+        }) else {
+            return rendered.to_string();
+        };
+        // (line number, rest of the line) for lines that have a gutter
+        let mut parsed: Vec<Option<(String, String)>> = lines
+            .iter()
+            .map(|line| {
+                let (prefix, rest) = (line.get(..width)?, line.get(width..)?);
+                let number = prefix.trim_end();
+                (number.chars().all(|c| c.is_ascii_digit()) && !rest.is_empty())
+                    .then(|| (number.to_string(), rest.to_string()))
+            })
+            .collect();
+
+        let mut i = 0;
+        while i < parsed.len() {
+            let is_location = |line: &Option<(String, String)>| {
+                line.as_ref().is_some_and(|(number, rest)| {
+                    number.is_empty() && (rest.starts_with("--> ") || rest.starts_with("::: "))
+                })
+            };
+            if !is_location(&parsed[i]) {
+                i += 1;
+                continue;
+            }
+            let location = parsed[i].as_ref().map(|(_, rest)| rest.clone());
+            let end = (i + 1..parsed.len())
+                .find(|&j| is_location(&parsed[j]))
+                .unwrap_or(parsed.len());
+            let mapped =
+                location.and_then(|location| self.map_block(&location, &parsed[i + 1..end]));
+            if let Some(mapped) = mapped {
+                for (j, line) in mapped.into_iter().enumerate() {
+                    parsed[i + j] = line;
+                }
+            }
+            i = end;
+        }
+
+        let width = parsed
+            .iter()
+            .flatten()
+            .map(|(number, _)| number.len())
+            .fold(width, usize::max);
+        lines
+            .iter()
+            .zip(parsed)
+            .map(|(line, parsed)| match parsed {
+                Some((number, rest)) => format!("{number:<width$}{rest}"),
+                None => line.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// A location line (`--> path:line:column`) and the snippet after it, mapped back to
+    /// the source.
+    fn map_block(
+        &self,
+        location: &str,
+        snippet: &[Option<(String, String)>],
+    ) -> Option<Vec<Option<(String, String)>>> {
+        let (arrow, location) = location.split_at(4);
+        let mut parts = location.rsplitn(3, ':');
+        let (column, line, path) = (parts.next()?, parts.next()?, parts.next()?);
+        let (source, line) = self.lookup(path, line.parse().ok()?)?;
+
+        let mut mapped = vec![Some((
+            String::new(),
+            format!("{arrow}{source}:{line}:{column}"),
+        ))];
+        for gutter_line in snippet {
+            mapped.push(match gutter_line {
+                Some((number, rest)) if !number.is_empty() => {
+                    let (_, line) = self.lookup(path, number.parse().ok()?)?;
+                    Some((line.to_string(), rest.clone()))
+                }
+                other => other.clone(),
+            });
+        }
+        Some(mapped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_locations() {
+        let mut lines = LineMap::default();
+        lines.record(9, 12);
+        lines.record(10, 13);
+        let mut source_map = SourceMap::default();
+        source_map.insert("foo/src/lib.rs".into(), "mod-foo/src/lib.rs".into(), lines);
+
+        let rendered = "error[E0412]: cannot find type `Reqwest` in this scope\n --> foo/src/lib.rs:9:28\n  |\n9 |     fn get(&self, client: Reqwest);\n  |                           ^^^^^^^ not found in this scope\n\n";
+        assert_eq!(
+            source_map.rewrite(rendered),
+            "error[E0412]: cannot find type `Reqwest` in this scope\n  --> mod-foo/src/lib.rs:12:28\n   |\n12 |     fn get(&self, client: Reqwest);\n   |                           ^^^^^^^ not found in this scope\n\n"
+        );
+
+        // synthesized lines aren't mapped, and neither are other files
+        for rendered in [
+            "error: oops\n --> foo/src/lib.rs:3:1\n  |\n3 | crate::__dylo_spec_3net!();\n  | ^\n",
+            "error: oops\n --> foo/src/.dylo/spec.rs:9:1\n  |\n9 | pub trait Mod {}\n  | ^\n",
+        ] {
+            assert_eq!(source_map.rewrite(rendered), rendered);
+        }
+    }
+}
@@ -0,0 +1,25 @@
+//! Records, next to each generated trait, a hash of the exported impl it was generated
+//! from, which `#[dylo::export]` checks when the mod builds: see `dylo_syntax::drift`.
+
+use dylo_syntax::drift::{hash_const_ident, impl_hash};
+use syn::{Attribute, Item, ItemImpl};
+
+/// The const `#[dylo::export]` checks the impl against, see [`hash_const_ident`].
+pub(crate) fn declare_hash(imp: &ItemImpl, attr: &Attribute, trait_ident: &syn::Ident) -> Item {
+    let args = match &attr.meta {
+        syn::Meta::List(list) => list.tokens.clone(),
+        _ => proc_macro2::TokenStream::new(),
+    };
+    let ident = hash_const_ident(trait_ident);
+    let hash = syn::LitInt::new(
+        &format!("0x{:016x}", impl_hash(imp, &args)),
+        proc_macro2::Span::call_site(),
+    );
+    let cfgs = imp.attrs.iter().filter(|attr| attr.path().is_ident("cfg"));
+    syn::parse_quote! {
+        #(#cfgs)*
+        #[doc(hidden)]
+        #[allow(dead_code, non_upper_case_globals)]
+        pub const #ident: u64 = #hash;
+    }
+}
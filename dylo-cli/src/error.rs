@@ -0,0 +1,120 @@
+use std::fmt;
+
+use camino::Utf8PathBuf;
+
+/// Why a mod's consumer couldn't be generated.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Reading or writing files failed
+    Io(std::io::Error),
+    /// A source file of the mod doesn't parse
+    Parse {
+        path: Utf8PathBuf,
+        error: syn::Error,
+    },
+    /// A `mod foo;` declaration doesn't point to any file
+    ModuleNotFound {
+        /// File with the declaration, relative to `src/`
+        path: Utf8PathBuf,
+        module: String,
+        /// Where the module was looked for, relative to `src/`
+        candidates: Vec<Utf8PathBuf>,
+    },
+    /// A `#[path]` attribute points outside of `src/`
+    ModulePath {
+        /// File with the declaration, relative to `src/`
+        path: Utf8PathBuf,
+        /// Spans the attribute's value
+        error: syn::Error,
+    },
+    /// `#[path]` attributes make a file declare itself, directly or not
+    ModuleCycle {
+        /// Files in declaration order, relative to `src/`, the first one repeated at the end
+        cycle: Vec<Utf8PathBuf>,
+    },
+    /// Exported impls can't be turned into dyn-compatible traits
+    NotDynCompatible {
+        mod_name: String,
+        /// One per problem, rendered like rustc's
+        diagnostics: Vec<String>,
+    },
+    /// The mod's `Cargo.toml` isn't what dylo expects
+    Manifest { path: Utf8PathBuf, message: String },
+    /// The mod is isolated, but its `Mod` trait can't go through the isolation boundary
+    Isolation { mod_name: String, message: String },
+    /// An environment variable cargo sets for build scripts is missing
+    Env { var: &'static str },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "{error}"),
+            Error::Parse { path, error } => write!(f, "failed to parse {path}: {error}"),
+            Error::ModuleNotFound {
+                path,
+                module,
+                candidates,
+            } => {
+                let candidates: Vec<String> = candidates
+                    .iter()
+                    .map(|candidate| format!("src/{candidate}"))
+                    .collect();
+                write!(
+                    f,
+                    "{path}: file not found for module `{module}` (looked for {})",
+                    candidates.join(" and ")
+                )
+            }
+            Error::ModulePath { path, error } => {
+                let start = error.span().start();
+                write!(f, "src/{path}:{}:{}: {error}", start.line, start.column + 1)
+            }
+            Error::ModuleCycle { cycle } => {
+                let cycle: Vec<String> = cycle.iter().map(|path| format!("src/{path}")).collect();
+                write!(
+                    f,
+                    "module declarations form a cycle: {}",
+                    cycle.join(" -> ")
+                )
+            }
+            Error::NotDynCompatible {
+                mod_name,
+                diagnostics,
+            } => {
+                for diagnostic in diagnostics {
+                    writeln!(f, "{diagnostic}")?;
+                }
+                write!(
+                    f,
+                    "refusing to generate {mod_name}: {} exported item(s) are not dyn-compatible",
+                    diagnostics.len()
+                )
+            }
+            Error::Manifest { path, message } => write!(f, "{path}: {message}"),
+            Error::Isolation { mod_name, message } => {
+                write!(f, "can't isolate {mod_name}: {message}")
+            }
+            Error::Env { var } => {
+                write!(f, "{var} is not set: is this running from a build script?")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            Error::Parse { error, .. } | Error::ModulePath { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
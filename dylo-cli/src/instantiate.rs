@@ -0,0 +1,42 @@
+//! Instantiations of exported traits that are generic over types:
+//! `#[dylo::export(instantiate(T = User, T = Session))]`, as parsed by
+//! `dylo_syntax::instantiate`.
+//!
+//! The generated trait stays generic (`dyn Store<User>` is dyn-compatible, it's generic
+//! methods that aren't), and each instantiation gets checked where the trait is declared,
+//! so a type that's missing from the consumer, or doesn't meet the trait's bounds, is
+//! reported there rather than at the first call.
+
+use dylo_syntax::instantiate::{Instantiation, Substitute};
+use syn::{GenericParam, Generics, Item, Path, Token, punctuated::Punctuated, visit_mut::VisitMut};
+
+/// Checks, wherever it's declared, that `trait_path` (the generic trait, with its
+/// associated types) is well-formed once instantiated. It takes a bound for rustc
+/// to check the trait's own bounds: a `dyn` type wouldn't do.
+pub(crate) fn check(instantiation: &Instantiation, generics: &Generics, trait_path: &Path) -> Item {
+    let mut trait_path = trait_path.clone();
+    Substitute(&instantiation.bindings).visit_path_mut(&mut trait_path);
+    let lifetimes = generics.lifetimes();
+    syn::parse_quote! {
+        const _: () = {
+            #[allow(dead_code)]
+            fn __dylo_instantiate<#(#lifetimes,)* DyloImpl: ?Sized + #trait_path>() {}
+        };
+    }
+}
+
+/// Generic parameters without their bounds, for use in type aliases (whose bounds aren't
+/// enforced, and warned about).
+pub(crate) fn unbounded_params(generics: &Generics) -> Punctuated<GenericParam, Token![,]> {
+    generics
+        .params
+        .iter()
+        .map(|param| match param {
+            GenericParam::Lifetime(lifetime) => {
+                GenericParam::Lifetime(syn::LifetimeParam::new(lifetime.lifetime.clone()))
+            }
+            GenericParam::Type(ty) => GenericParam::Type(ty.ident.clone().into()),
+            GenericParam::Const(constant) => GenericParam::Const(constant.clone()),
+        })
+        .collect()
+}
@@ -0,0 +1,362 @@
+//! Code generation for isolated mods, which can run in a child process: see the
+//! `isolation` module of dylo-runtime for the other half.
+//!
+//! Mods opt in with `[package.metadata.dylo] isolation = true` in their `Cargo.toml`.
+//! dylo-cli then generates, for the `Mod` trait:
+//!
+//!   * in the consumer, a proxy that implements `Mod` by serializing arguments and
+//!     sending them to the child process
+//!   * in the mod, a dispatcher that deserializes arguments, calls the real `ModImpl`,
+//!     and serializes results back.
+
+use proc_macro2::{Ident, Span, TokenStream, TokenTree};
+use quote::{ToTokens, format_ident, quote};
+use syn::{FnArg, Item, ItemTrait, ReturnType, TraitItem, TraitItemFn, Type};
+
+/// Whether the mod's manifest has `[package.metadata.dylo] isolation = true`.
+pub(crate) fn isolation_enabled(doc: &toml_edit::DocumentMut) -> bool {
+    doc.get("package")
+        .and_then(|p| p.get("metadata"))
+        .and_then(|m| m.get("dylo"))
+        .and_then(|d| d.get("isolation"))
+        .and_then(|i| i.as_bool())
+        .unwrap_or(false)
+}
+
+/// Generates the consumer-side proxy for `trait_item`, along with its `Isolate` impl.
+pub(crate) fn generate_proxy(trait_item: &ItemTrait) -> Result<Vec<Item>, String> {
+    let trait_ident = &trait_item.ident;
+    let proxy_ident = format_ident!("Dylo{}Proxy", trait_ident);
+    let alias_ident = format_ident!("Dyn{}", trait_ident);
+    let unsafety = &trait_item.unsafety;
+
+    let mut methods = Vec::new();
+    for method in trait_methods(trait_item)? {
+        let name = method.sig.ident.to_string();
+        let mut sig = method.sig.clone();
+        let mut arg_idents = Vec::new();
+        for (i, input) in sig.inputs.iter_mut().enumerate() {
+            if let FnArg::Typed(pat_type) = input {
+                let ident = format_ident!("arg{}", i);
+                *pat_type.pat = syn::parse_quote!(#ident);
+                arg_idents.push(ident);
+            }
+        }
+
+        let on_error = if returns_result(&sig.output) {
+            quote! { ::std::result::Result::Err(::std::convert::From::from(e)) }
+        } else {
+            // nothing to return the error as: see "Isolated modules" in dylo-runtime's README
+            let message = format!(
+                "{{e}} (`{name}` doesn't return a `Result`, so it can't report errors from the module process)"
+            );
+            quote! { ::std::panic!(#message) }
+        };
+
+        methods.push(quote! {
+            #sig {
+                match self.client.call(#name, &(#(#arg_idents,)*)) {
+                    ::std::result::Result::Ok(result) => result,
+                    ::std::result::Result::Err(e) => #on_error,
+                }
+            }
+        });
+    }
+
+    let items = quote! {
+        /// Forwards every call to the module running in a child process.
+        #[doc(hidden)]
+        pub struct #proxy_ident {
+            client: ::dylo_runtime::details::isolation::Client,
+        }
+
+        #unsafety impl #trait_ident for #proxy_ident {
+            #(#methods)*
+        }
+
+        impl ::dylo_runtime::details::isolation::Isolate for #alias_ident {
+            fn proxy(client: ::dylo_runtime::details::isolation::Client) -> &'static Self {
+                ::std::boxed::Box::leak(::std::boxed::Box::new(#proxy_ident { client }))
+            }
+        }
+    };
+    Ok(syn::parse2::<syn::File>(items)
+        .expect("generated proxy must parse")
+        .items)
+}
+
+/// Generates the mod-side dispatcher for `trait_item`.
+pub(crate) fn generate_dispatch(trait_item: &ItemTrait) -> Result<Vec<Item>, String> {
+    let alias_ident = format_ident!("Dyn{}", trait_item.ident);
+
+    let mut arms = Vec::new();
+    for method in trait_methods(trait_item)? {
+        let method_ident = &method.sig.ident;
+        let name = method_ident.to_string();
+
+        let mut arg_idents = Vec::new();
+        let mut arg_types = Vec::new();
+        let mut call_args = Vec::new();
+        for (i, input) in method.sig.inputs.iter().enumerate() {
+            if let FnArg::Typed(pat_type) = input {
+                let ident = format_ident!("arg{}", i);
+                match &*pat_type.ty {
+                    Type::Reference(reference) => {
+                        arg_types.push(owned_type(&reference.elem));
+                        call_args.push(quote! { &#ident });
+                    }
+                    ty => {
+                        arg_types.push(ty.to_token_stream());
+                        call_args.push(quote! { #ident });
+                    }
+                }
+                arg_idents.push(ident);
+            }
+        }
+
+        arms.push(quote! {
+            #name => {
+                let (#(#arg_idents,)*): (#(#arg_types,)*) =
+                    ::dylo_runtime::details::isolation::decode_args(method, args)?;
+                let result = m.#method_ident(#(#call_args),*);
+                ::dylo_runtime::details::isolation::encode_result(method, &result)
+            }
+        });
+    }
+
+    let items = quote! {
+        /// Entry point for calls made to this module when it runs in a child process.
+        ///
+        /// See <https://github.com/bearcove/dylo>
+        #[doc(hidden)]
+        #[unsafe(export_name = "github.com_bearcove_dylo_dispatch")]
+        pub extern "Rust" fn dylo_dispatch(
+            method: &str,
+            args: &str,
+        ) -> ::std::result::Result<::std::string::String, ::std::string::String> {
+            ::std::thread_local! {
+                static MOD: &'static crate::#alias_ident = crate::awaken();
+            }
+            let m: &'static crate::#alias_ident = MOD.with(|m| *m);
+
+            match method {
+                #(#arms)*
+                _ => ::std::result::Result::Err(::std::format!("unknown method {method}")),
+            }
+        }
+    };
+    Ok(syn::parse2::<syn::File>(items)
+        .expect("generated dispatcher must parse")
+        .items)
+}
+
+/// Returns the trait's methods, or an error listing every method that can't
+/// be called across a process boundary.
+fn trait_methods(trait_item: &ItemTrait) -> Result<Vec<&TraitItemFn>, String> {
+    let mut methods = Vec::new();
+    let mut problems = Vec::new();
+
+    for item in &trait_item.items {
+        let method = match item {
+            // provided methods call the others, which get proxied
+            TraitItem::Fn(method) if method.default.is_some() => continue,
+            TraitItem::Fn(method) => method,
+            TraitItem::Type(assoc) => {
+                problems.push(format!(
+                    "`{}`: associated types aren't supported by isolation",
+                    assoc.ident
+                ));
+                continue;
+            }
+            _ => continue,
+        };
+        let name = &method.sig.ident;
+
+        for input in &method.sig.inputs {
+            match input {
+                FnArg::Receiver(receiver) => {
+                    let is_shared_ref = matches!(
+                        &*receiver.ty,
+                        Type::Reference(r) if r.mutability.is_none()
+                    );
+                    if !is_shared_ref {
+                        problems.push(format!("`{name}`: only `&self` receivers are supported"));
+                    }
+                }
+                FnArg::Typed(pat_type) => {
+                    if let Type::Reference(r) = &*pat_type.ty {
+                        if r.mutability.is_some() {
+                            problems.push(format!(
+                                "`{name}`: `&mut` arguments can't be sent to another process"
+                            ));
+                        }
+                    }
+                    if mentions_trait_object(&pat_type.ty.to_token_stream()) {
+                        problems.push(format!(
+                            "`{name}`: `dyn`/`impl` arguments can't be serialized"
+                        ));
+                    }
+                }
+            }
+        }
+
+        if let ReturnType::Type(_, ty) = &method.sig.output {
+            if matches!(&**ty, Type::Reference(_)) {
+                problems.push(format!(
+                    "`{name}`: returning references from another process is not possible"
+                ));
+            }
+            if mentions_trait_object(&ty.to_token_stream()) {
+                problems.push(format!(
+                    "`{name}`: `dyn`/`impl` return types can't be serialized"
+                ));
+            }
+            if let Some(error) = foreign_error_type(ty) {
+                problems.push(format!(
+                    "`{name}`: the error type `{error}` can't carry an `IsolationError`, use an error type of your own that implements `From<IsolationError>` and `Deserialize`"
+                ));
+            }
+        }
+
+        methods.push(method);
+    }
+
+    if problems.is_empty() {
+        Ok(methods)
+    } else {
+        Err(format!(
+            "trait {} can't be isolated:\n  {}",
+            trait_item.ident,
+            problems.join("\n  ")
+        ))
+    }
+}
+
+/// For `&T` arguments: the owned type to deserialize into, so the dispatcher
+/// can pass `&owned` (deref coercion takes care of `&String` → `&str` and the like).
+fn owned_type(elem: &Type) -> TokenStream {
+    let is_unsized = match elem {
+        Type::Slice(_) => true,
+        Type::Path(path) => path.path.segments.last().is_some_and(|s| {
+            ["str", "Path", "OsStr", "CStr"].contains(&s.ident.to_string().as_str())
+        }),
+        _ => false,
+    };
+    if is_unsized {
+        quote! { <#elem as ::std::borrow::ToOwned>::Owned }
+    } else {
+        elem.to_token_stream()
+    }
+}
+
+fn returns_result(output: &ReturnType) -> bool {
+    match output {
+        ReturnType::Type(_, ty) => match &**ty {
+            Type::Path(path) => path
+                .path
+                .segments
+                .last()
+                .is_some_and(|s| s.ident == "Result"),
+            _ => false,
+        },
+        ReturnType::Default => false,
+    }
+}
+
+/// For methods returning a `Result`, returns the error type if it's one that
+/// can't possibly implement `From<IsolationError>` and `Deserialize`: types
+/// from the standard library (or primitives), which this crate doesn't own.
+///
+/// Other error types are only checked when the consumer gets compiled.
+fn foreign_error_type(ty: &Type) -> Option<String> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let last = path.path.segments.last()?;
+    if last.ident != "Result" {
+        return None;
+    }
+
+    let syn::PathArguments::AngleBracketed(args) = &last.arguments else {
+        return None;
+    };
+    let error = args.args.iter().nth(1).and_then(|arg| match arg {
+        syn::GenericArgument::Type(ty) => Some(ty),
+        _ => None,
+    });
+    let Some(error) = error else {
+        // `io::Result<T>`, `fmt::Result`... but not a crate's own `Result<T>` alias
+        let module = path.path.segments.iter().rev().nth(1)?;
+        return ["io", "fmt", "thread"]
+            .contains(&module.ident.to_string().as_str())
+            .then(|| format!("{}::Error", module.ident));
+    };
+
+    let is_foreign = match error {
+        Type::Tuple(_) | Type::Array(_) | Type::Slice(_) | Type::Reference(_) | Type::Never(_) => {
+            true
+        }
+        Type::Path(error_path) => {
+            let segments = &error_path.path.segments;
+            let first = segments.first().map(|s| s.ident.to_string());
+            let std_root = first
+                .as_deref()
+                .is_some_and(|first| ["std", "core", "alloc"].contains(&first));
+            let prelude = segments.len() == 1
+                && [
+                    "String", "Box", "Vec", "Option", "bool", "char", "str", "u8", "u16", "u32",
+                    "u64", "u128", "usize", "i8", "i16", "i32", "i64", "i128", "isize", "f32",
+                    "f64",
+                ]
+                .contains(&first.as_deref().unwrap_or_default());
+            std_root || prelude
+        }
+        _ => false,
+    };
+    is_foreign.then(|| error.to_token_stream().to_string().replace(' ', ""))
+}
+
+fn mentions_trait_object(tokens: &TokenStream) -> bool {
+    tokens.clone().into_iter().any(|tt| match tt {
+        TokenTree::Ident(ident) => ident == Ident::new("dyn", Span::call_site()) || ident == "impl",
+        TokenTree::Group(group) => mentions_trait_object(&group.stream()),
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn isolation_problems(trait_src: &str) -> Result<usize, String> {
+        let trait_item: ItemTrait = syn::parse_str(trait_src).unwrap();
+        trait_methods(&trait_item).map(|methods| methods.len())
+    }
+
+    #[test]
+    fn own_error_types_are_fine() {
+        assert_eq!(
+            isolation_problems(
+                "pub trait Mod { fn parse(&self, input: String) -> Result<u32, ParseError>; fn alias(&self) -> Result<u32>; fn count(&self) -> u32; }"
+            ),
+            Ok(3)
+        );
+    }
+
+    #[test]
+    fn foreign_error_types_are_rejected() {
+        let err = isolation_problems(
+            "pub trait Mod { fn a(&self) -> Result<u32, std::num::ParseIntError>; fn b(&self) -> Result<(), String>; fn c(&self) -> std::io::Result<u32>; fn d(&self) -> Result<u32, ()>; fn e(&self) -> Result<u32, Box<dyn std::error::Error>>; }",
+        )
+        .unwrap_err();
+        for needle in [
+            "`a`: the error type `std::num::ParseIntError`",
+            "`b`: the error type `String`",
+            "`c`: the error type `io::Error`",
+            "`d`: the error type `()`",
+            "`e`: the error type `Box<dynstd::error::Error>`",
+        ] {
+            assert!(err.contains(needle), "{needle:?} not in {err}");
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//! Generates the consumer crates of dylo mods, along with the `spec.rs` files that
+//! `#[dylo::export]` relies on. This is what the `dylo` binary runs: tools that want to
+//! drive generation themselves can use [`Generator`].
+
+pub mod build;
+mod cfg;
+mod codegen;
+// what the `dylo` binary runs, not part of the library's API
+#[cfg(feature = "cli")]
+#[doc(hidden)]
+pub mod command;
+#[cfg(feature = "cli")]
+mod dependency;
+mod diagnostics;
+mod drift;
+mod error;
+mod instantiate;
+mod isolation;
+mod lint;
+mod modtree;
+mod render;
+mod types;
+#[cfg(feature = "cli")]
+mod verify;
+mod workspace;
+
+pub use codegen::{Generated, Generator};
+pub use diagnostics::SourceMap;
+pub use error::Error;
+pub use types::{ModInfo, Scope};
+pub use workspace::FileSet;
+
+const SPEC_PATH: &str = ".dylo/spec.rs";
+const SUPPORT_PATH: &str = ".dylo/support.rs";
+/// First line of every Rust file dylo generates: files that don't start with it aren't
+/// dylo's to delete.
+const GENERATED_HEADER: &str = "// This file is automatically @generated by dylo";
+
+#[cfg(test)]
+mod tests;
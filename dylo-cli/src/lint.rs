@@ -0,0 +1,556 @@
+//! Checks `#[dylo::export]` impls against the documented limitations (see the
+//! dylo crate's README) before generating anything, so that problems are reported
+//! against the mod's source rather than as a `cargo check` failure in the consumer.
+
+use std::{collections::HashSet, fmt::Write as _};
+
+use camino::Utf8Path;
+use proc_macro2::Span;
+use quote::ToTokens as _;
+use syn::{
+    Attribute, FnArg, GenericParam, ImplItem, Item, ItemImpl, ReturnType, Signature, Type,
+    spanned::Spanned as _, visit::Visit,
+};
+
+use dylo_syntax::{args::ExportArgs, is_dylo_export, is_dylo_provided};
+
+/// A problem with an exported impl, pointing into the mod's source.
+pub(crate) struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+    pub help: String,
+}
+
+impl Diagnostic {
+    fn new(span: Span, message: impl Into<String>, help: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            span,
+            help: help.into(),
+        }
+    }
+
+    /// Renders the diagnostic like rustc would, `path` being the file `source` was read from.
+    pub fn render(&self, path: &Utf8Path, source: &str) -> String {
+        let start = self.span.start();
+        let end = self.span.end();
+        let line = source
+            .lines()
+            .nth(start.line.saturating_sub(1))
+            .unwrap_or("");
+        let line_number = start.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+
+        let underline_end = if end.line == start.line {
+            end.column
+        } else {
+            line.chars().count()
+        };
+        let underline = format!(
+            "{}{}",
+            " ".repeat(start.column),
+            "^".repeat(underline_end.saturating_sub(start.column).max(1))
+        );
+
+        let mut out = String::new();
+        let _ = writeln!(out, "error: {}", self.message);
+        let _ = writeln!(
+            out,
+            "{gutter}--> {path}:{}:{}",
+            start.line,
+            start.column + 1
+        );
+        let _ = writeln!(out, "{gutter} |");
+        let _ = writeln!(out, "{line_number} | {line}");
+        let _ = writeln!(out, "{gutter} | {underline}");
+        let _ = writeln!(out, "{gutter} |");
+        let _ = writeln!(out, "{gutter} = help: {}", self.help);
+        out
+    }
+}
+
+/// Checks every `#[dylo::export]` impl in `items`, including in inline modules.
+/// `con_items` are the same items as the consumer gets them (see [`transform_ast`]):
+/// provided methods can only use what's left there.
+///
+/// [`transform_ast`]: crate::codegen::transform_ast
+pub(crate) fn lint_exports(items: &[Item], con_items: &[Item]) -> Vec<Diagnostic> {
+    let scope = Scope::new(items, con_items);
+    let mut diagnostics = Vec::new();
+    for item in items {
+        match item {
+            Item::Impl(imp) => {
+                if let Some(attr) = imp.attrs.iter().find(|attr| is_dylo_export(attr)) {
+                    lint_impl(imp, attr, &scope, &mut diagnostics);
+                }
+            }
+            Item::Mod(item_mod) => {
+                if let Some((_, content)) = &item_mod.content {
+                    let con_content = con_items
+                        .iter()
+                        .find_map(|item| match item {
+                            Item::Mod(con_mod) if con_mod.ident == item_mod.ident => {
+                                con_mod.content.as_ref().map(|(_, content)| &content[..])
+                            }
+                            _ => None,
+                        })
+                        .unwrap_or_default();
+                    diagnostics.extend(lint_exports(content, con_content));
+                }
+            }
+            _ => {}
+        }
+    }
+    diagnostics
+}
+
+/// What the provided methods of the exported impls of a module can't use.
+struct Scope {
+    /// Names of items the mod declares, but the consumer doesn't get
+    impl_only: HashSet<String>,
+    /// Names of inherent methods, which are impl-only as far as the trait is concerned
+    inherent_methods: HashSet<String>,
+}
+
+impl Scope {
+    fn new(items: &[Item], con_items: &[Item]) -> Self {
+        let con_names = declared_names(con_items);
+        let impl_only = declared_names(items)
+            .into_iter()
+            .filter(|name| !con_names.contains(name))
+            .collect();
+        let inherent_methods = items
+            .iter()
+            .filter_map(|item| match item {
+                Item::Impl(imp) if imp.trait_.is_none() => Some(&imp.items),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|item| match item {
+                ImplItem::Fn(f) => Some(f.sig.ident.to_string()),
+                _ => None,
+            })
+            .collect();
+        Self {
+            impl_only,
+            inherent_methods,
+        }
+    }
+}
+
+fn declared_names(items: &[Item]) -> HashSet<String> {
+    fn use_names(tree: &syn::UseTree, names: &mut HashSet<String>) {
+        match tree {
+            syn::UseTree::Path(path) => use_names(&path.tree, names),
+            syn::UseTree::Name(name) => {
+                names.insert(name.ident.to_string());
+            }
+            syn::UseTree::Rename(rename) => {
+                names.insert(rename.rename.to_string());
+            }
+            syn::UseTree::Glob(_) => {}
+            syn::UseTree::Group(group) => {
+                for tree in &group.items {
+                    use_names(tree, names);
+                }
+            }
+        }
+    }
+
+    let mut names = HashSet::new();
+    for item in items {
+        let ident = match item {
+            Item::Const(item) => &item.ident,
+            Item::Enum(item) => &item.ident,
+            Item::Fn(item) => &item.sig.ident,
+            Item::Mod(item) => &item.ident,
+            Item::Static(item) => &item.ident,
+            Item::Struct(item) => &item.ident,
+            Item::Trait(item) => &item.ident,
+            Item::Type(item) => &item.ident,
+            Item::Union(item) => &item.ident,
+            Item::Macro(item) => match &item.ident {
+                Some(ident) => ident,
+                None => continue,
+            },
+            Item::Use(item) => {
+                use_names(&item.tree, &mut names);
+                continue;
+            }
+            _ => continue,
+        };
+        names.insert(ident.to_string());
+    }
+    names
+}
+
+fn lint_impl(imp: &ItemImpl, attr: &Attribute, scope: &Scope, diagnostics: &mut Vec<Diagnostic>) {
+    let args = match ExportArgs::from_attr(attr) {
+        Ok(args) => args,
+        Err(e) => {
+            diagnostics.push(Diagnostic::new(
+                e.span(),
+                e.to_string(),
+                "expected something like `#[dylo::export(nonsync, supertraits(Debug), instantiate(T = User))]`",
+            ));
+            ExportArgs::default()
+        }
+    };
+    lint_args(imp, attr, &args, diagnostics);
+    lint_instantiations(imp, attr, &args, diagnostics);
+
+    let trait_methods: HashSet<String> = imp
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            ImplItem::Fn(f) => Some(f.sig.ident.to_string()),
+            _ => None,
+        })
+        .collect();
+
+    for item in &imp.items {
+        match item {
+            ImplItem::Fn(method) => {
+                lint_signature(&method.sig, diagnostics);
+                if method.attrs.iter().any(is_dylo_provided) {
+                    lint_provided(method, scope, &trait_methods, diagnostics);
+                }
+            }
+            ImplItem::Const(constant) => {
+                let name = &constant.ident;
+                diagnostics.push(Diagnostic::new(
+                    constant.span(),
+                    format!("`{name}` is an associated const, which is not dyn-compatible"),
+                    format!(
+                        "turn it into a method instead: `fn {}(&self) -> {}`",
+                        name.to_string().to_lowercase(),
+                        constant.ty.to_token_stream()
+                    ),
+                ));
+            }
+            ImplItem::Type(assoc) if !assoc.generics.params.is_empty() => {
+                diagnostics.push(Diagnostic::new(
+                    assoc.generics.span(),
+                    format!("`{}` is a generic associated type", assoc.ident),
+                    "generic associated types are not dyn-compatible, drop the parameters",
+                ));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Arguments that parse fine, but don't agree with the impl they're on.
+fn lint_args(
+    imp: &ItemImpl,
+    attr: &Attribute,
+    args: &ExportArgs,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    match (args.unsafety, imp.unsafety) {
+        (true, None) => diagnostics.push(Diagnostic::new(
+            attr.span(),
+            "`unsafe` generates an `unsafe trait`, but this impl isn't `unsafe`",
+            "write `unsafe impl`, or remove `unsafe` from the arguments",
+        )),
+        (false, Some(unsafety)) => diagnostics.push(Diagnostic::new(
+            unsafety.span(),
+            "`unsafe impl` of a trait that isn't generated as `unsafe trait`",
+            "add `unsafe` to the arguments: `#[dylo::export(unsafe)]`",
+        )),
+        _ => {}
+    }
+
+    let is_mod = imp
+        .trait_
+        .as_ref()
+        .and_then(|(_, path, _)| path.segments.last())
+        .is_some_and(|segment| segment.ident == "Mod");
+    if is_mod && (args.name.is_some() || args.vis.is_some()) {
+        diagnostics.push(Diagnostic::new(
+            attr.span(),
+            "`Mod` cannot be renamed or made less visible",
+            "the loader refers to `crate::Mod`: remove `name = ...` and `vis = ...`",
+        ));
+    }
+}
+
+fn lint_instantiations(
+    imp: &ItemImpl,
+    attr: &Attribute,
+    args: &ExportArgs,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let type_params: Vec<&syn::TypeParam> = imp.generics.type_params().collect();
+    for param in &imp.generics.params {
+        if matches!(param, GenericParam::Const(_)) {
+            diagnostics.push(Diagnostic::new(
+                param.span(),
+                "exported traits cannot be generic over consts",
+                "only lifetime and type parameters are allowed",
+            ));
+        }
+    }
+
+    let is_mod = imp
+        .trait_
+        .as_ref()
+        .and_then(|(_, path, _)| path.segments.last())
+        .is_some_and(|segment| segment.ident == "Mod");
+    if is_mod && !type_params.is_empty() {
+        diagnostics.push(Diagnostic::new(
+            imp.generics.span(),
+            "`Mod` cannot be generic over types",
+            "the loader has no way to pick the types: return generic traits from `Mod`'s methods instead",
+        ));
+        return;
+    }
+
+    if args.instantiations.is_empty() {
+        for param in &type_params {
+            diagnostics.push(Diagnostic::new(
+                param.span(),
+                "exported traits cannot be generic over types without instantiations",
+                format!(
+                    "list the types it's used with, e.g. `#[dylo::export(instantiate({} = MyType))]`",
+                    param.ident
+                ),
+            ));
+        }
+        return;
+    }
+
+    if type_params.is_empty() {
+        diagnostics.push(Diagnostic::new(
+            attr.span(),
+            "nothing to instantiate",
+            "`instantiate(...)` is for impls with type parameters, remove it",
+        ));
+        return;
+    }
+
+    for instantiation in &args.instantiations {
+        for (param, _) in &instantiation.bindings {
+            if !type_params.iter().any(|p| p.ident == *param) {
+                diagnostics.push(Diagnostic::new(
+                    param.span(),
+                    format!("`{param}` is not a type parameter of this impl"),
+                    "bind the impl's type parameters, e.g. `T = MyType` for `impl<T> Trait<T>`",
+                ));
+            }
+        }
+        for param in &type_params {
+            let count = instantiation
+                .bindings
+                .iter()
+                .filter(|(p, _)| *p == param.ident)
+                .count();
+            if count != 1 {
+                let span = instantiation
+                    .bindings
+                    .first()
+                    .map_or_else(|| attr.span(), |(p, _)| p.span());
+                diagnostics.push(Diagnostic::new(
+                    span,
+                    format!("instantiation must give exactly one type for `{}`", param.ident),
+                    "group the types of one instantiation in parentheses: `instantiate((K = u64, V = User))`",
+                ));
+            }
+        }
+    }
+}
+
+/// Provided methods end up in the consumer, as default methods of the trait: they
+/// can't rely on anything the consumer doesn't have, including the impl's own type.
+fn lint_provided(
+    method: &syn::ImplItemFn,
+    scope: &Scope,
+    trait_methods: &HashSet<String>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    struct Checker<'a> {
+        name: &'a syn::Ident,
+        scope: &'a Scope,
+        trait_methods: &'a HashSet<String>,
+        diagnostics: &'a mut Vec<Diagnostic>,
+    }
+
+    fn is_self(expr: &syn::Expr) -> bool {
+        matches!(expr, syn::Expr::Path(p) if p.path.is_ident("self"))
+    }
+
+    impl<'ast> Visit<'ast> for Checker<'_> {
+        fn visit_path(&mut self, path: &'ast syn::Path) {
+            let first = path
+                .segments
+                .first()
+                .filter(|_| path.leading_colon.is_none());
+            if let Some(first) = first {
+                if self.scope.impl_only.contains(&first.ident.to_string()) {
+                    self.diagnostics.push(Diagnostic::new(
+                        first.ident.span(),
+                        format!(
+                            "`{}` is provided, but uses `{}`, which consumers don't have",
+                            self.name, first.ident
+                        ),
+                        "provided methods are compiled in the consumer too: only use items that aren't gated behind the `impl` feature",
+                    ));
+                }
+            }
+            syn::visit::visit_path(self, path);
+        }
+
+        fn visit_expr_field(&mut self, i: &'ast syn::ExprField) {
+            if is_self(&i.base) {
+                self.diagnostics.push(Diagnostic::new(
+                    i.span(),
+                    format!("`{}` is provided, but accesses a field of `self`", self.name),
+                    "provided methods only know `self` through the trait: call one of its methods instead",
+                ));
+            }
+            syn::visit::visit_expr_field(self, i);
+        }
+
+        fn visit_expr_method_call(&mut self, i: &'ast syn::ExprMethodCall) {
+            let method = i.method.to_string();
+            if is_self(&i.receiver)
+                && !self.trait_methods.contains(&method)
+                && self.scope.inherent_methods.contains(&method)
+            {
+                self.diagnostics.push(Diagnostic::new(
+                    i.method.span(),
+                    format!(
+                        "`{}` is provided, but calls `{method}`, which isn't part of the exported trait",
+                        self.name
+                    ),
+                    format!("export `{method}` too, or only call it from required methods"),
+                ));
+            }
+            syn::visit::visit_expr_method_call(self, i);
+        }
+
+        // nested items have their own scope
+        fn visit_item(&mut self, _i: &'ast Item) {}
+    }
+
+    let name = &method.sig.ident;
+    if let Some(asyncness) = &method.sig.asyncness {
+        diagnostics.push(Diagnostic::new(
+            asyncness.span(),
+            format!("`{name}` is provided, and async"),
+            "provided methods can't be async (yet): return a boxed future, or make it a required method",
+        ));
+    }
+
+    let mut checker = Checker {
+        name,
+        scope,
+        trait_methods,
+        diagnostics,
+    };
+    checker.visit_block(&method.block);
+}
+
+fn lint_signature(sig: &Signature, diagnostics: &mut Vec<Diagnostic>) {
+    let name = &sig.ident;
+
+    let requires_sized = sig.generics.where_clause.as_ref().is_some_and(|wc| {
+        wc.predicates.iter().any(|predicate| match predicate {
+            syn::WherePredicate::Type(pt) => {
+                matches!(&pt.bounded_ty, Type::Path(p) if p.path.is_ident("Self"))
+                    && pt.bounds.iter().any(|bound| match bound {
+                        syn::TypeParamBound::Trait(t) => t.path.is_ident("Sized"),
+                        _ => false,
+                    })
+            }
+            _ => false,
+        })
+    });
+    if requires_sized {
+        // not part of the vtable, anything goes
+        return;
+    }
+
+    for param in &sig.generics.params {
+        if !matches!(param, GenericParam::Lifetime(_)) {
+            diagnostics.push(Diagnostic::new(
+                param.span(),
+                format!("`{name}` has generic type parameters"),
+                "take a `&dyn Trait` or `Box<dyn Trait>` instead",
+            ));
+        }
+    }
+
+    match sig.inputs.first() {
+        Some(FnArg::Receiver(receiver)) => {
+            if receiver.reference.is_none() && receiver.colon_token.is_none() {
+                diagnostics.push(Diagnostic::new(
+                    receiver.span(),
+                    format!("`{name}` takes `self` by value"),
+                    "take `self: Box<Self>` instead (or `&self` / `&mut self`)",
+                ));
+            }
+        }
+        _ => {
+            diagnostics.push(Diagnostic::new(
+                sig.ident.span(),
+                format!("`{name}` has no `self` receiver"),
+                "add a `&self` receiver, or a `where Self: Sized` bound to leave it out of the exported trait object",
+            ));
+        }
+    }
+
+    for input in &sig.inputs {
+        if let FnArg::Typed(pat_type) = input {
+            if let Some(span) = find_impl_trait(&pat_type.ty) {
+                diagnostics.push(Diagnostic::new(
+                    span,
+                    format!("`{name}` takes an `impl Trait` argument"),
+                    "take a `&dyn Trait` or `Box<dyn Trait>` instead",
+                ));
+            }
+            if is_bare_self(&pat_type.ty) {
+                diagnostics.push(Diagnostic::new(
+                    pat_type.ty.span(),
+                    format!("`{name}` takes `Self` by value"),
+                    "take `Box<Self>` or `&Self` instead",
+                ));
+            }
+        }
+    }
+
+    if let ReturnType::Type(_, ty) = &sig.output {
+        if let Some(span) = find_impl_trait(ty) {
+            diagnostics.push(Diagnostic::new(
+                span,
+                format!("`{name}` returns `impl Trait`"),
+                "return a `Box<dyn Trait>` instead",
+            ));
+        }
+        if is_bare_self(ty) {
+            diagnostics.push(Diagnostic::new(
+                ty.span(),
+                format!("`{name}` returns `Self` by value"),
+                "return `Box<Self>` instead",
+            ));
+        }
+    }
+}
+
+fn is_bare_self(ty: &Type) -> bool {
+    matches!(ty, Type::Path(p) if p.qself.is_none() && p.path.is_ident("Self"))
+}
+
+fn find_impl_trait(ty: &Type) -> Option<Span> {
+    struct Finder(Option<Span>);
+
+    impl<'ast> Visit<'ast> for Finder {
+        fn visit_type_impl_trait(&mut self, i: &'ast syn::TypeImplTrait) {
+            self.0.get_or_insert(i.span());
+        }
+    }
+
+    let mut finder = Finder(None);
+    finder.visit_type(ty);
+    finder.0
+}
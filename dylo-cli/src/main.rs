@@ -1,18 +1,9 @@
 use camino::Utf8PathBuf;
-use command::{parse_args, run_command};
+use dylo_cli::{
+    Scope,
+    command::{parse_args, run_command},
+};
 use tracing_subscriber::{layer::SubscriberExt as _, util::SubscriberInitExt as _};
-use types::Scope;
-
-// note: init_template and load_template are NOT modules here
-
-pub mod codegen;
-pub mod command;
-pub mod dependency;
-pub mod types;
-pub mod workspace;
-
-const SPEC_PATH: &str = ".dylo/spec.rs";
-const SUPPORT_PATH: &str = ".dylo/support.rs";
 
 fn setup_tracing_subscriber() {
     let filter = std::env::var("RUST_LOG")
@@ -83,6 +74,3 @@ fn main() -> eyre::Result<()> {
 
     Ok(())
 }
-
-#[cfg(test)]
-mod tests;
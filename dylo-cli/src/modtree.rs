@@ -0,0 +1,361 @@
+//! Follows `mod foo;` declarations from a mod's `src/lib.rs`, so that mods can be
+//! split across several files. Every file goes through [`transform_ast`], and the
+//! traits exported from submodules end up in `spec.rs` as macros, which the
+//! submodules invoke to get their traits at their proper paths.
+//!
+//! Invocations are added to the consumer by [`transform_ast`], and to the mod's
+//! sources by [`ModFile::with_spec_invocations`].
+
+use std::collections::HashMap;
+
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use syn::{Attribute, Item, ItemMod, ext::IdentExt as _};
+
+use crate::{
+    cfg::eval_cfg_attrs,
+    codegen::{ModuleSpec, transform_ast},
+    error::Error,
+};
+
+/// A source file of a mod crate, along with its transformed (consumer) version.
+pub(crate) struct ModFile {
+    /// Path relative to the crate's `src/` directory, e.g. `net/http.rs`
+    pub rel_path: Utf8PathBuf,
+    /// Module path from the crate root, e.g. `["net", "http"]` (empty for `lib.rs`)
+    pub module_path: Vec<String>,
+    /// Contents of the file, as found on disk
+    pub source: String,
+    /// Parsed contents of the file, before any transformation
+    pub ast: syn::File,
+    /// Inner attributes (`#![...]`) for the consumer version of the file
+    pub con_attrs: Vec<Attribute>,
+    /// Items for the consumer version of the file
+    pub con_items: Vec<Item>,
+    /// Traits exported from this file's module and its inline modules
+    pub specs: Vec<ModuleSpec>,
+}
+
+impl ModFile {
+    /// Returns the source of this file with invocations of spec macros added
+    /// where they're missing, or `None` if there are none to add.
+    pub fn with_spec_invocations(&self) -> Option<String> {
+        // (byte offset, text to insert) for inline modules
+        let mut insertions: Vec<(usize, String)> = Vec::new();
+        let mut append = None;
+
+        for spec in &self.specs {
+            if spec.is_root() {
+                continue;
+            }
+            let invocation = format!("crate::{}!();", spec.macro_name());
+
+            let inline_path = &spec.module_path[self.module_path.len()..];
+            if inline_path.is_empty() {
+                if !spec.is_invoked_in(&self.ast.items) {
+                    append = Some(invocation);
+                }
+                continue;
+            }
+
+            let Some((brace, content)) =
+                find_inline_mod(&self.ast.items, inline_path).and_then(|m| m.content.as_ref())
+            else {
+                continue;
+            };
+            if spec.is_invoked_in(content) {
+                continue;
+            }
+
+            // insert right before the closing brace, on a line of its own if the brace has one
+            let close = brace.span.close().byte_range().start;
+            let line_start = self.source[..close].rfind('\n').map_or(0, |i| i + 1);
+            let indent = &self.source[line_start..close];
+            if indent.trim().is_empty() {
+                insertions.push((line_start, format!("{indent}    {invocation}\n")));
+            } else if self.source[..close].ends_with(char::is_whitespace) {
+                insertions.push((close, format!("{invocation} ")));
+            } else {
+                insertions.push((close, format!(" {invocation} ")));
+            }
+        }
+
+        if insertions.is_empty() && append.is_none() {
+            return None;
+        }
+
+        let mut source = self.source.clone();
+        insertions.sort_by_key(|(offset, _)| std::cmp::Reverse(*offset));
+        for (offset, text) in insertions {
+            source.insert_str(offset, &text);
+        }
+        if let Some(invocation) = append {
+            source = format!("{}\n\n{invocation}\n", source.trim_end());
+        }
+        Some(source)
+    }
+}
+
+fn find_inline_mod<'a>(items: &'a [Item], inline_path: &[String]) -> Option<&'a ItemMod> {
+    let (first, rest) = inline_path.split_first()?;
+    let item_mod = items.iter().find_map(|item| match item {
+        Item::Mod(item_mod) if item_mod.content.is_some() && item_mod.ident.unraw() == first => {
+            Some(item_mod)
+        }
+        _ => None,
+    })?;
+    if rest.is_empty() {
+        Some(item_mod)
+    } else {
+        find_inline_mod(&item_mod.content.as_ref()?.1, rest)
+    }
+}
+
+/// Replaces `mod foo;` declarations with inline modules, holding the (consumer) inner
+/// attributes and items of their file, as found in `files` by module path. This is how
+/// consumers generated by their build script fit in a single file (see [`crate::build`]).
+pub(crate) fn inline_mod_files(
+    items: &mut [Item],
+    module_path: &[String],
+    files: &HashMap<Vec<String>, (Vec<Attribute>, Vec<Item>)>,
+) {
+    for item in items {
+        let Item::Mod(item_mod) = item else {
+            continue;
+        };
+        let module_path = [module_path, &[item_mod.ident.unraw().to_string()]].concat();
+        if let Some((_, content)) = &mut item_mod.content {
+            inline_mod_files(content, &module_path, files);
+            continue;
+        }
+        let Some((attrs, file_items)) = files.get(&module_path) else {
+            continue;
+        };
+        let mut file_items = file_items.clone();
+        inline_mod_files(&mut file_items, &module_path, files);
+        item_mod.attrs.retain(|attr| !attr.path().is_ident("path"));
+        item_mod.attrs.extend(attrs.iter().cloned());
+        item_mod.content = Some((Default::default(), file_items));
+        item_mod.semi = None;
+    }
+}
+
+/// Reads and transforms `src/lib.rs` and every file reachable from it through
+/// `mod foo;` declarations (minus the ones that are only there for the impl).
+///
+/// The first file returned is always `lib.rs`.
+pub(crate) fn transform_mod_tree(src_dir: &Utf8Path) -> Result<Vec<ModFile>, Error> {
+    let mut files = Vec::new();
+    transform_file(
+        src_dir,
+        Utf8PathBuf::from("lib.rs"),
+        true,
+        Vec::new(),
+        &mut Vec::new(),
+        &mut files,
+    )?;
+    Ok(files)
+}
+
+/// `ancestors` are the files that (transitively) declare this one, as canonical
+/// paths and as given: `#[path]` can point back at any of them.
+fn transform_file(
+    src_dir: &Utf8Path,
+    rel_path: Utf8PathBuf,
+    is_mod_rs: bool,
+    module_path: Vec<String>,
+    ancestors: &mut Vec<(std::path::PathBuf, Utf8PathBuf)>,
+    files: &mut Vec<ModFile>,
+) -> Result<(), Error> {
+    let canonical = fs_err::canonicalize(src_dir.join(&rel_path))?;
+    if let Some(start) = ancestors.iter().position(|(path, _)| *path == canonical) {
+        let mut cycle: Vec<Utf8PathBuf> = ancestors[start..]
+            .iter()
+            .map(|(_, rel_path)| rel_path.clone())
+            .collect();
+        cycle.push(rel_path);
+        return Err(Error::ModuleCycle { cycle });
+    }
+
+    let source = fs_err::read_to_string(src_dir.join(&rel_path))?;
+    let ast = syn::parse_file(&source).map_err(|error| Error::Parse {
+        path: src_dir.join(&rel_path),
+        error,
+    })?;
+
+    let mut con_items = ast.items.clone();
+    let mut specs = Vec::new();
+    transform_ast(&mut con_items, &module_path, &mut specs);
+
+    // inner attributes go through the same `cfg` evaluation as items: a file that's
+    // `#![cfg(feature = "impl")]` is empty in the consumer
+    let mut con_attrs = ast.attrs.clone();
+    if !eval_cfg_attrs(&mut con_attrs) {
+        con_attrs.clear();
+        con_items.clear();
+    }
+
+    let file = FileLocation {
+        src_dir,
+        rel_path: &rel_path,
+        is_mod_rs,
+    };
+    let mut children = Vec::new();
+    visit_mods(&file, &module_path, &[], &con_items, &mut children)?;
+
+    files.push(ModFile {
+        rel_path: rel_path.clone(),
+        module_path,
+        source,
+        ast,
+        con_attrs,
+        con_items,
+        specs,
+    });
+
+    ancestors.push((canonical, rel_path));
+    for (child_path, child_is_mod_rs, child_module_path) in children {
+        transform_file(
+            src_dir,
+            child_path,
+            child_is_mod_rs,
+            child_module_path,
+            ancestors,
+            files,
+        )?;
+    }
+    ancestors.pop();
+    Ok(())
+}
+
+struct FileLocation<'a> {
+    src_dir: &'a Utf8Path,
+    rel_path: &'a Utf8Path,
+    /// `lib.rs`, `mod.rs`, or a file loaded through `#[path]`: these own their directory
+    is_mod_rs: bool,
+}
+
+impl FileLocation<'_> {
+    fn dir(&self) -> &Utf8Path {
+        self.rel_path.parent().unwrap_or(Utf8Path::new(""))
+    }
+
+    /// Where `mod foo;` declared in this file looks for `foo.rs`
+    fn child_dir(&self) -> Utf8PathBuf {
+        if self.is_mod_rs {
+            self.dir().to_owned()
+        } else {
+            self.dir().join(self.rel_path.file_stem().unwrap())
+        }
+    }
+}
+
+/// Finds `mod foo;` declarations (including in inline modules), and resolves them to files.
+fn visit_mods(
+    file: &FileLocation<'_>,
+    module_path: &[String],
+    inline_path: &[String],
+    items: &[Item],
+    children: &mut Vec<(Utf8PathBuf, bool, Vec<String>)>,
+) -> Result<(), Error> {
+    for item in items {
+        let Item::Mod(item_mod) = item else {
+            continue;
+        };
+        let name = item_mod.ident.unraw().to_string();
+
+        match &item_mod.content {
+            Some((_, content)) => {
+                let inline_path = [inline_path, &[name]].concat();
+                visit_mods(file, module_path, &inline_path, content, children)?;
+            }
+            None => {
+                let (child_path, child_is_mod_rs) = resolve_mod(file, inline_path, item_mod)?;
+                let child_module_path = [module_path, inline_path, &[name]].concat();
+                children.push((child_path, child_is_mod_rs, child_module_path));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether `path` (relative to `src/`) is relative, and doesn't go up out of `src/`.
+fn stays_inside(path: &Utf8Path) -> bool {
+    let mut depth = 0usize;
+    for component in path.components() {
+        match component {
+            Utf8Component::Normal(_) => depth += 1,
+            Utf8Component::CurDir => {}
+            Utf8Component::ParentDir => match depth.checked_sub(1) {
+                Some(parent) => depth = parent,
+                None => return false,
+            },
+            Utf8Component::RootDir | Utf8Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+/// Resolves `mod foo;` to `foo.rs` or `foo/mod.rs`, honoring `#[path]`, following
+/// the rules in <https://doc.rust-lang.org/reference/items/modules.html#module-source-filenames>
+fn resolve_mod(
+    file: &FileLocation<'_>,
+    inline_path: &[String],
+    item_mod: &ItemMod,
+) -> Result<(Utf8PathBuf, bool), Error> {
+    let path_attr = item_mod.attrs.iter().find_map(|attr| {
+        if !attr.path().is_ident("path") {
+            return None;
+        }
+        match &attr.meta.require_name_value().ok()?.value {
+            syn::Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Str(lit),
+                ..
+            }) => Some(lit.clone()),
+            _ => None,
+        }
+    });
+
+    if let Some(lit) = path_attr {
+        let base = if inline_path.is_empty() {
+            file.dir().to_owned()
+        } else {
+            inline_path
+                .iter()
+                .fold(file.child_dir(), |dir, segment| dir.join(segment))
+        };
+        let path = base.join(lit.value());
+        // the consumer's copy goes to the same path in its own `src/`
+        if !stays_inside(&path) {
+            return Err(Error::ModulePath {
+                path: file.rel_path.to_owned(),
+                error: syn::Error::new(
+                    lit.span(),
+                    format!(
+                        "`#[path]` must point inside of `src/`, `{}` does not",
+                        lit.value()
+                    ),
+                ),
+            });
+        }
+        return Ok((path, true));
+    }
+
+    let base = inline_path
+        .iter()
+        .fold(file.child_dir(), |dir, segment| dir.join(segment));
+    let name = item_mod.ident.unraw().to_string();
+
+    let non_mod_rs = base.join(format!("{name}.rs"));
+    if file.src_dir.join(&non_mod_rs).exists() {
+        return Ok((non_mod_rs, false));
+    }
+    let mod_rs = base.join(&name).join("mod.rs");
+    if file.src_dir.join(&mod_rs).exists() {
+        return Ok((mod_rs, true));
+    }
+    Err(Error::ModuleNotFound {
+        path: file.rel_path.to_owned(),
+        module: name,
+        candidates: vec![non_mod_rs, mod_rs],
+    })
+}
@@ -0,0 +1,373 @@
+//! Renders consumer files from their mod's source rather than with prettyplease, so that
+//! comments, blank lines and formatting survive, and consumers can be diffed against
+//! their mods.
+//!
+//! The consumer AST (see [`crate::codegen::transform_ast`]) is mostly made of tokens
+//! cloned from the mod's source, with their spans: those are copied from the source as
+//! written, along with whatever whitespace and comments separate them. Where tokens were
+//! removed (impl-only items, members, attributes), their byte ranges are skipped. The few
+//! tokens dylo synthesizes (simplified `cfg`s, spec invocations, `include!`s) are printed
+//! compactly.
+//!
+//! The result is parsed back and compared to the AST it's supposed to represent: if they
+//! differ, callers fall back to prettyplease. Along with it comes the line each copied
+//! token came from, see [`LineMap`].
+
+use std::ops::Range;
+
+use proc_macro2::{Delimiter, Spacing, TokenStream, TokenTree};
+use quote::ToTokens as _;
+
+use crate::diagnostics::LineMap;
+
+/// Renders `file` (the consumer version of `source`), or returns `None` if the tokens
+/// couldn't be laid out faithfully.
+pub(crate) fn render_preserving(source: &str, file: &syn::File) -> Option<(String, LineMap)> {
+    let original = {
+        let mut leaves = Vec::new();
+        flatten(source.parse().ok()?, &mut leaves);
+        let mut ranges: Vec<Range<usize>> = leaves.into_iter().filter_map(|l| l.range).collect();
+        ranges.sort_by_key(|range| range.start);
+        ranges
+    };
+    // doc comments are lexed into `#[doc = "..."]`, all spanned somewhere in the comment
+    let comments: Vec<&Range<usize>> = original
+        .iter()
+        .filter(|range| is_comment(&source[(*range).clone()]))
+        .collect();
+
+    let mut leaves = Vec::new();
+    flatten(file.to_token_stream(), &mut leaves);
+    for leaf in &mut leaves {
+        let Some(range) = leaf.range.clone() else {
+            continue;
+        };
+        if let Some(comment) = comments
+            .iter()
+            .find(|comment| comment.start <= range.start && range.end <= comment.end)
+        {
+            leaf.range = Some((*comment).clone());
+        } else if source.get(range) != Some(leaf.text.as_str()) {
+            // spans of synthesized tokens are empty, but tokens that were cloned and then
+            // changed (like a rebased `include_str!` path) keep theirs: those get printed
+            leaf.range = None;
+        }
+    }
+
+    let renderer = Renderer { source, original };
+    let (rendered, lines) = renderer.render(&leaves);
+
+    let reparsed = syn::parse_file(&rendered).ok()?;
+    if reparsed.to_token_stream().to_string() != file.to_token_stream().to_string() {
+        tracing::debug!("formatting-preserving rendering didn't round-trip, falling back");
+        return None;
+    }
+    Some((rendered, lines))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Open(Delimiter),
+    Close(Delimiter),
+    Punct(char, Spacing),
+    Word,
+}
+
+struct Leaf {
+    kind: Kind,
+    text: String,
+    /// Byte range in the mod's source, for tokens that come from it
+    range: Option<Range<usize>>,
+}
+
+fn flatten(tokens: TokenStream, out: &mut Vec<Leaf>) {
+    for token in tokens {
+        match token {
+            TokenTree::Group(group) => {
+                let delimiter = group.delimiter();
+                let (open, close) = match delimiter {
+                    Delimiter::Parenthesis => ("(", ")"),
+                    Delimiter::Brace => ("{", "}"),
+                    Delimiter::Bracket => ("[", "]"),
+                    Delimiter::None => {
+                        flatten(group.stream(), out);
+                        continue;
+                    }
+                };
+                out.push(Leaf {
+                    kind: Kind::Open(delimiter),
+                    text: open.to_string(),
+                    range: non_empty(group.span_open().byte_range()),
+                });
+                flatten(group.stream(), out);
+                out.push(Leaf {
+                    kind: Kind::Close(delimiter),
+                    text: close.to_string(),
+                    range: non_empty(group.span_close().byte_range()),
+                });
+            }
+            TokenTree::Punct(punct) => out.push(Leaf {
+                kind: Kind::Punct(punct.as_char(), punct.spacing()),
+                text: punct.as_char().to_string(),
+                range: non_empty(punct.span().byte_range()),
+            }),
+            TokenTree::Ident(ident) => out.push(Leaf {
+                kind: Kind::Word,
+                text: ident.to_string(),
+                range: non_empty(ident.span().byte_range()),
+            }),
+            TokenTree::Literal(literal) => out.push(Leaf {
+                kind: Kind::Word,
+                text: literal.to_string(),
+                range: non_empty(literal.span().byte_range()),
+            }),
+        }
+    }
+}
+
+fn non_empty(range: Range<usize>) -> Option<Range<usize>> {
+    (!range.is_empty()).then_some(range)
+}
+
+struct Renderer<'a> {
+    source: &'a str,
+    /// Byte ranges of every token of the source, sorted by start
+    original: Vec<Range<usize>>,
+}
+
+impl Renderer<'_> {
+    fn render(&self, leaves: &[Leaf]) -> (String, LineMap) {
+        let mut out = String::new();
+        let mut lines = LineMap::default();
+        // where each line of the source starts, and how many lines `out` had so far
+        let line_starts: Vec<usize> = std::iter::once(0)
+            .chain(self.source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        let (mut counted, mut line) = (0, 1);
+        // how much of the source has been copied (or skipped) so far
+        let mut upto = 0;
+        // whether the previous token was copied from the source
+        let mut prev_original = true;
+        let mut prev: Option<&Leaf> = None;
+        let mut prev_prev: Option<&Leaf> = None;
+
+        for leaf in leaves {
+            match &leaf.range {
+                Some(range) => {
+                    if range.start < upto {
+                        // part of a doc comment that's already been copied
+                        continue;
+                    }
+                    if prev_original {
+                        out.push_str(&self.gap(upto, range.start));
+                    } else if let Some(indent) = self.line_indent(range.start) {
+                        out.push('\n');
+                        out.push_str(indent);
+                    } else {
+                        out.push_str(spacing(prev_prev, prev, leaf));
+                    }
+                    let text = &self.source[range.clone()];
+                    line += out[counted..].matches('\n').count();
+                    counted = out.len();
+                    let source_line = line_starts.partition_point(|&start| start <= range.start);
+                    for i in 0..=text.matches('\n').count() {
+                        lines.record(line + i, source_line + i);
+                    }
+                    out.push_str(text);
+                    upto = range.end;
+                    prev_original = true;
+                }
+                None => {
+                    let ends_item = prev.is_some_and(|prev| {
+                        matches!(
+                            prev.kind,
+                            Kind::Punct(';', _) | Kind::Close(Delimiter::Brace)
+                        )
+                    });
+                    if ends_item {
+                        // items added after removed ones keep the blank line that preceded them
+                        let next = self.original.partition_point(|range| range.start < upto);
+                        let next_start = self.original.get(next).map_or(upto, |range| range.start);
+                        if prev_original && has_blank_line(&self.source[upto..next_start]) {
+                            out.push('\n');
+                        }
+                        out.push('\n');
+                        out.push_str(self.indent_before(upto));
+                    } else {
+                        out.push_str(spacing(prev_prev, prev, leaf));
+                    }
+                    out.push_str(&leaf.text);
+                    prev_original = false;
+                }
+            }
+            prev_prev = prev;
+            prev = Some(leaf);
+        }
+
+        if prev_original {
+            out.push_str(&self.gap(upto, self.source.len()));
+        }
+        // the file may have started or ended with removed items
+        let trimmed = out.trim();
+        lines.shift(
+            -(out[..out.len() - out.trim_start().len()]
+                .matches('\n')
+                .count() as isize),
+        );
+        if trimmed.is_empty() {
+            (String::new(), lines)
+        } else {
+            (format!("{trimmed}\n"), lines)
+        }
+    }
+
+    /// What goes between two tokens copied from the source: the source in between if
+    /// nothing was removed there. Otherwise, comments attached to the removed tokens
+    /// (not separated from them by a blank line) go with them.
+    fn gap(&self, from: usize, to: usize) -> String {
+        let first = self.original.partition_point(|range| range.start < from);
+        let removed: Vec<&Range<usize>> = self.original[first..]
+            .iter()
+            .take_while(|range| range.start < to)
+            .collect();
+        let (Some(first_removed), Some(last_end)) =
+            (removed.first(), removed.iter().map(|range| range.end).max())
+        else {
+            return self.source[from..to].to_string();
+        };
+
+        let leading = &self.source[from..first_removed.start];
+        let trailing = &self.source[last_end.min(to)..to];
+        if !leading.contains('\n') && !trailing.contains('\n') {
+            return if leading.len() < trailing.len() {
+                leading
+            } else {
+                trailing
+            }
+            .to_string();
+        }
+        match last_blank_line(leading) {
+            // what follows was kept apart from what precedes by the removed tokens' blank line
+            Some(end) if !has_blank_line(trailing) => format!("{}\n{trailing}", &leading[..end]),
+            Some(end) => format!("{}{trailing}", &leading[..end]),
+            // a comment at the end of the last kept line belongs to it, and a block
+            // doesn't start with a blank line
+            None => {
+                let first_line = leading.split('\n').next().unwrap_or_default();
+                if self.source[..from].ends_with('{') {
+                    format!("{first_line}{}", without_blank_lines(trailing))
+                } else {
+                    format!("{first_line}{trailing}")
+                }
+            }
+        }
+    }
+
+    /// Indentation of the line `offset` is on, if only whitespace precedes it there.
+    fn line_indent(&self, offset: usize) -> Option<&str> {
+        let line_start = self.source[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let indent = &self.source[line_start..offset];
+        indent.trim().is_empty().then_some(indent)
+    }
+
+    /// Indentation of the line that ends at (or contains) `offset`.
+    fn indent_before(&self, offset: usize) -> &str {
+        let line_start = self.source[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let line = &self.source[line_start..offset];
+        &line[..line.len() - line.trim_start().len()]
+    }
+}
+
+/// Where the text before the last blank line of `s` ends, if it has one. `s` is a gap
+/// between tokens: its first line is the rest of a token's line, and its last line is
+/// the indentation of the next token, neither counts.
+fn last_blank_line(s: &str) -> Option<usize> {
+    let lines: Vec<&str> = s.split('\n').collect();
+    let blank = (1..lines.len().saturating_sub(1))
+        .rev()
+        .find(|&i| lines[i].trim().is_empty())?;
+    Some(
+        lines[..blank]
+            .iter()
+            .map(|line| line.len() + 1)
+            .sum::<usize>()
+            - 1,
+    )
+}
+
+/// `s` (a gap between tokens, see [`last_blank_line`]) without its blank lines.
+fn without_blank_lines(s: &str) -> String {
+    let lines: Vec<&str> = s.split('\n').collect();
+    let last = lines.len().saturating_sub(1);
+    lines
+        .iter()
+        .enumerate()
+        .filter(|&(i, line)| i == 0 || i == last || !line.trim().is_empty())
+        .map(|(_, line)| *line)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn is_comment(text: &str) -> bool {
+    text.starts_with("//") || text.starts_with("/*")
+}
+
+fn has_blank_line(s: &str) -> bool {
+    last_blank_line(s).is_some()
+}
+
+/// Spacing for synthesized tokens, which are simple: attributes, paths, macro calls.
+fn spacing(prev_prev: Option<&Leaf>, prev: Option<&Leaf>, cur: &Leaf) -> &'static str {
+    let Some(prev) = prev else {
+        return "";
+    };
+    let tight = match (prev.kind, cur.kind) {
+        (Kind::Open(Delimiter::Brace), Kind::Close(Delimiter::Brace)) => true,
+        (Kind::Open(Delimiter::Brace), _) | (_, Kind::Close(Delimiter::Brace)) => false,
+        (Kind::Open(_), _) | (_, Kind::Close(_)) => true,
+        (_, Kind::Punct(',' | ';' | '.' | ':', _)) => true,
+        (Kind::Punct('.' | '#', _), _) => true,
+        (Kind::Punct(_, Spacing::Joint), _) => true,
+        // the second colon of a `::`
+        (Kind::Punct(':', _), _) => {
+            prev_prev.is_some_and(|pp| matches!(pp.kind, Kind::Punct(':', Spacing::Joint)))
+        }
+        (Kind::Word, Kind::Punct('!', _)) => true,
+        (Kind::Punct('!', _), Kind::Open(_)) => true,
+        (Kind::Word, Kind::Open(Delimiter::Parenthesis | Delimiter::Bracket)) => true,
+        _ => false,
+    };
+    if tight { "" } else { " " }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::transform_ast;
+
+    fn render(source: &str) -> String {
+        let mut file = syn::parse_file(source).unwrap();
+        transform_ast(&mut file.items, &[], &mut Vec::new());
+        render_preserving(source, &file).unwrap().0
+    }
+
+    #[test]
+    fn keeps_comments_and_formatting() {
+        let source = "// What this is about.\n\npub struct Config {\n    pub name: String, // shown to users\n    #[cfg(feature = \"impl\")]\n    secret: String,\n}\n\n/* section */\n\n// only for the impl\n#[cfg(feature = \"impl\")]\nfn helper() -> u32 {\n    1\n}\n\npub fn   weird_spacing( a : u32 ) {}\n";
+        assert_eq!(
+            render(source),
+            "// What this is about.\n\npub struct Config {\n    pub name: String, // shown to users\n}\n\n/* section */\n\npub fn   weird_spacing( a : u32 ) {}\n"
+        );
+    }
+
+    #[test]
+    fn prints_synthesized_tokens() {
+        let source = "#[cfg(any(feature = \"impl\", unix))]\nfn a() {}\n#[cfg_attr(not(feature = \"impl\"), derive(Debug))]\npub struct B(u32, #[cfg(feature = \"impl\")] u64);\n\npub mod net {\n    pub struct C;\n\n    #[cfg(feature = \"impl\")]\n    struct NetImpl;\n\n    #[dylo::export]\n    impl Net for NetImpl {}\n}\n";
+        let mut file = syn::parse_file(source).unwrap();
+        transform_ast(&mut file.items, &[], &mut Vec::new());
+        assert_eq!(
+            render_preserving(source, &file).unwrap().0,
+            "#[cfg(unix)]\nfn a() {}\n#[derive(Debug)]\npub struct B(u32);\n\npub mod net {\n    pub struct C;\n\n    crate::__dylo_spec_3net!();\n}\n"
+        );
+    }
+}
@@ -0,0 +1,30 @@
+use dylo_runtime::details::isolation::IsolationError;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub enum ParseError {
+    InvalidNumber(String),
+    Isolation(String),
+}
+
+impl From<IsolationError> for ParseError {
+    fn from(e: IsolationError) -> Self {
+        Self::Isolation(e.to_string())
+    }
+}
+
+#[cfg(feature = "impl")]
+#[derive(Default)]
+struct ModImpl;
+
+#[dylo::export]
+impl Mod for ModImpl {
+    fn greet(&self, name: &str) -> String {
+        format!("Hello, {name}!")
+    }
+
+    fn parse(&self, input: String) -> Result<u32, ParseError> {
+        input
+            .parse()
+            .map_err(|e: std::num::ParseIntError| ParseError::InvalidNumber(e.to_string()))
+    }
+}
@@ -0,0 +1,96 @@
+#[cfg(feature = "impl")]
+#[derive(Default)]
+struct ModImpl;
+
+#[dylo::export]
+impl Mod for ModImpl {
+    type Error = std::io::Error;
+
+    type Items<'a> = std::slice::Iter<'a, u32>;
+
+    const MAX_RETRIES: u32 = 3;
+
+    fn fine(&self, input: &str) -> Box<dyn Iterator<Item = u32>> {
+        todo!()
+    }
+
+    fn sized_only() -> Self
+    where
+        Self: Sized,
+    {
+        todo!()
+    }
+
+    fn generic<T: Default>(&self) -> T {
+        todo!()
+    }
+
+    fn arg_impl(&self, f: impl Fn(u32) -> u32) {}
+
+    fn ret_impl(&self) -> impl Iterator<Item = u32> {
+        std::iter::empty()
+    }
+
+    fn consume(self) {}
+
+    fn constructor() -> Box<Self> {
+        todo!()
+    }
+}
+
+#[dylo::export]
+impl<T> Store<T> for StoreImpl<T> {
+    fn get(&self, id: u64) -> Option<T> {
+        None
+    }
+}
+
+#[dylo::export(instantiate(T = u32, U = u64))]
+impl<T> Cache<T> for CacheImpl<T> {
+    fn get(&self, id: u64) -> Option<T> {
+        None
+    }
+}
+
+#[dylo::export(sendable)]
+impl Other for OtherImpl {}
+
+#[cfg(feature = "impl")]
+fn impl_helper() -> u32 {
+    1
+}
+
+impl ProvidedImpl {
+    fn inherent(&self) -> u32 {
+        2
+    }
+}
+
+#[dylo::export]
+impl Provided for ProvidedImpl {
+    fn required(&self) -> u32 {
+        0
+    }
+
+    #[dylo::provided]
+    fn fine(&self) -> u32 {
+        self.required() + 1
+    }
+
+    #[dylo::provided]
+    fn broken(&self) -> u32 {
+        impl_helper() + self.inherent() + self.count
+    }
+
+    #[dylo::provided]
+    async fn later(&self) {}
+}
+
+#[dylo::export(unsafe)]
+impl Careful for CarefulImpl {}
+
+#[dylo::export]
+unsafe impl Careless for CarelessImpl {}
+
+#[dylo::export(name = "Root", vis = "pub(crate)")]
+impl Mod for RenamedImpl {}
@@ -11,11 +11,71 @@ fn not_impl_only() {}
 #[derive(Default)]
 struct ModImpl;
 
+/// Entry point of the module.
 #[dylo::export]
+#[must_use]
 impl Mod for ModImpl {
+    type Error = std::num::ParseIntError;
+
+    /// Always 42.
+    #[inline]
+    #[deprecated = "use `parse` instead"]
     fn foo(&self) -> u32 {
         42
     }
+
+    #[tracing::instrument(skip(self))]
+    #[allow(clippy::needless_lifetimes)]
+    #[cfg_attr(unix, inline, doc = "Parses `s` (on unix).")]
+    fn parse(&self, s: &str) -> Result<u32, Self::Error> {
+        s.parse()
+    }
+
+    /// Like `parse`, but with a fallback.
+    #[dylo::provided]
+    fn parse_or(&self, s: &str, mut fallback: u32) -> u32 {
+        if fallback == 0 {
+            fallback = NotImplEnum::Variant1.variant1();
+        }
+        self.parse(s).unwrap_or(fallback)
+    }
+
+    async fn fetch(&self, url: &str) -> String {
+        url.to_string()
+    }
+
+    async fn sum(&self, (a, mut b): (u32, u32)) -> u32 {
+        b += 1;
+        a + b
+    }
+}
+
+pub trait Record {
+    fn id(&self) -> u64;
+}
+
+#[cfg(feature = "impl")]
+struct StoreImpl<T>(std::marker::PhantomData<T>);
+
+#[dylo::export(instantiate(T = User, T = Session), forward(stability::unstable))]
+impl<T: Record + Send + Sync + 'static> Store<T> for StoreImpl<T> {
+    #[stability::unstable(feature = "store")]
+    #[inline(always)]
+    fn get(&self, id: u64) -> Option<T> {
+        None
+    }
+}
+
+#[cfg(feature = "impl")]
+#[derive(Debug)]
+struct RawClientImpl(*mut u8);
+
+/// Talks to the device directly: callers uphold its invariants.
+#[dylo::export(unsend, unsafe, supertraits(std::fmt::Debug), name = "RawClient", vis = "pub(crate)")]
+unsafe impl Client for RawClientImpl {
+    type Handle = u32;
+
+    async fn poke(&self, addr: usize) {}
 }
 
 #[cfg(feature = "impl")]
@@ -41,3 +101,57 @@ impl NotImplEnum {
         23
     }
 }
+
+#[cfg(all(feature = "impl", unix))]
+fn impl_unix_only() {}
+
+#[cfg(any(feature = "impl", unix))]
+fn impl_or_unix() {}
+
+#[cfg(not(any(test, feature = "impl")))]
+fn consumer_only() {}
+
+#[cfg_attr(not(feature = "impl"), derive(Debug))]
+pub struct WithFields {
+    #[cfg(any(test, feature = "impl"))]
+    secret: u32,
+    #[cfg_attr(all(feature = "impl", unix), serde(skip))]
+    public: u32,
+}
+
+pub enum Nested {
+    Public,
+    #[cfg(feature = "impl")]
+    Internal(ImplType),
+    Mixed {
+        shown: u32,
+        #[cfg(feature = "impl")]
+        hidden: ImplType,
+    },
+    Tuple(u32, #[cfg(feature = "impl")] ImplType),
+}
+
+impl Nested {
+    pub fn public(&self) -> u32 {
+        match self {
+            Nested::Public => 1,
+            #[cfg(feature = "impl")]
+            Nested::Internal(_) => 2,
+            _ => 3,
+        }
+    }
+
+    #[cfg(feature = "impl")]
+    fn internal(&self) -> ImplType {
+        #[cfg(feature = "impl")]
+        let x = ImplType::new();
+        x
+    }
+}
+
+pub trait Helper {
+    fn public(&self);
+
+    #[cfg(feature = "impl")]
+    fn internal(&self) -> ImplType;
+}
@@ -7,11 +7,465 @@ fn snapshot_simple_module() {
     let input_rs = include_str!("testdata/simple-module.rs");
     let mut file = syn::parse_file(input_rs).unwrap();
 
-    let mut added_items = Vec::new();
-    transform_ast(&mut file.items, &mut added_items);
+    let mut specs = Vec::new();
+    transform_ast(&mut file.items, &[], &mut specs);
 
-    file.items.extend(added_items);
+    file.items
+        .extend(specs.into_iter().flat_map(|spec| spec.items));
 
     let output = prettyplease::unparse(&file);
     insta::assert_snapshot!(output);
 }
+
+#[test]
+fn snapshot_isolated_module() {
+    let input_rs = include_str!("testdata/isolated-module.rs");
+    let mut file = syn::parse_file(input_rs).unwrap();
+
+    let mut specs = Vec::new();
+    transform_ast(&mut file.items, &[], &mut specs);
+
+    let mod_trait = specs[0]
+        .items
+        .iter()
+        .find_map(|item| match item {
+            syn::Item::Trait(trait_item) if trait_item.ident == "Mod" => Some(trait_item.clone()),
+            _ => None,
+        })
+        .unwrap();
+
+    let mut items = isolation::generate_proxy(&mod_trait).unwrap();
+    items.extend(isolation::generate_dispatch(&mod_trait).unwrap());
+
+    let output = prettyplease::unparse(&syn::File {
+        shebang: None,
+        attrs: Default::default(),
+        items,
+    });
+    insta::assert_snapshot!(output);
+}
+
+#[test]
+fn snapshot_multi_file_module() {
+    let dir = tempfile::tempdir().unwrap();
+    let src_dir = camino::Utf8Path::from_path(dir.path()).unwrap();
+    for (path, contents) in [
+        (
+            "lib.rs",
+            "#![doc = include_str!(\"../README.md\")]\n#![deny(missing_docs)]\n#![cfg_attr(docsrs, feature(doc_cfg))]\n#![cfg_attr(feature = \"impl\", allow(dead_code))]\npub mod net;\n#[path = \"custom.rs\"]\npub mod other;\n#[cfg(feature = \"impl\")]\nmod imp;\n\n// Versioned APIs, kept as written.\npub mod api {\n    #[cfg(feature = \"impl\")]\n    struct ClientImpl;\n\n    #[dylo::export]\n    impl Client for ClientImpl {\n        fn call(&self) -> u32 {\n            1\n        }\n    }\n\n    pub mod v2 { #[dylo::export] impl Client for ClientImpl { fn call(&self) -> u32 { 2 } } }\n}\n",
+        ),
+        (
+            "net.rs",
+            "//! Networking, see [`http`].\n#![doc = include_str!(\"net.md\")]\npub mod http;\npub mod tls;\n#[cfg(feature = \"impl\")]\nstruct NetImpl;\n#[dylo::export]\nimpl Net for NetImpl {\n    fn get(&self, req: http::Request) -> u32 {\n        42\n    }\n}\n",
+        ),
+        (
+            "net/http/mod.rs",
+            "pub struct Request;\n#[cfg(feature = \"impl\")]\npub fn secret() {}\n",
+        ),
+        (
+            "net/tls.rs",
+            "#![cfg(feature = \"impl\")]\npub fn handshake() {}\n",
+        ),
+        (
+            "custom.rs",
+            "pub mod inner {\n    #[cfg(feature = \"impl\")]\n    pub fn hidden() {}\n    mod deeper;\n}\n",
+        ),
+        ("inner/deeper.rs", "pub fn visible() {}\n"),
+    ] {
+        let path = src_dir.join(path);
+        fs_err::create_dir_all(path.parent().unwrap()).unwrap();
+        fs_err::write(path, contents).unwrap();
+    }
+
+    let files = modtree::transform_mod_tree(src_dir).unwrap();
+
+    let mut output = String::new();
+    for file in &files {
+        output.push_str(&format!(
+            "// src/{} (crate::{}, exports from {:?})\n",
+            file.rel_path,
+            file.module_path.join("::"),
+            file.specs
+                .iter()
+                .map(|spec| spec.module_path.join("::"))
+                .collect::<Vec<_>>()
+        ));
+        let mut con_attrs = file.con_attrs.clone();
+        codegen::rebase_includes(&mut con_attrs, &file.rel_path, "mod-foo");
+        let con_ast = syn::File {
+            shebang: None,
+            attrs: con_attrs,
+            items: file.con_items.clone(),
+        };
+        output.push_str(&render::render_preserving(&file.source, &con_ast).unwrap().0);
+        if let Some(source) = file.with_spec_invocations() {
+            output.push_str(&format!("// mod source with invocations:\n{source}"));
+        }
+        output.push('\n');
+    }
+    insta::assert_snapshot!(output);
+}
+
+#[test]
+fn spec_macro_names_dont_collide() {
+    let macro_name = |module_path: &[&str]| {
+        codegen::ModuleSpec {
+            module_path: module_path.iter().map(|s| s.to_string()).collect(),
+            items: Vec::new(),
+        }
+        .macro_name()
+    };
+
+    assert_eq!(macro_name(&[]), "__dylo_spec");
+    assert_eq!(macro_name(&["net", "http"]), "__dylo_spec_3net_4http");
+
+    let paths: &[&[&str]] = &[
+        &["a", "b"],
+        &["a__b"],
+        &["a_", "b"],
+        &["a", "_b"],
+        &["a_1b"],
+        &["a", "1b"],
+    ];
+    let names: std::collections::HashSet<String> =
+        paths.iter().map(|path| macro_name(path)).collect();
+    assert_eq!(names.len(), paths.len(), "{names:?}");
+}
+
+#[test]
+fn path_cycles_are_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let src_dir = camino::Utf8Path::from_path(dir.path()).unwrap();
+    for (path, contents) in [
+        ("lib.rs", "pub mod a;\n"),
+        ("a.rs", "#[path = \"b.rs\"]\npub mod b;\n"),
+        ("b.rs", "#[path = \"./a.rs\"]\npub mod a;\n"),
+    ] {
+        fs_err::write(src_dir.join(path), contents).unwrap();
+    }
+
+    let Err(err) = modtree::transform_mod_tree(src_dir) else {
+        panic!("cycle not detected");
+    };
+    assert_eq!(
+        err.to_string(),
+        "module declarations form a cycle: src/a.rs -> src/b.rs -> src/./a.rs"
+    );
+
+    // a file declaring itself is a cycle too
+    fs_err::write(src_dir.join("a.rs"), "#[path = \"a.rs\"]\npub mod a;\n").unwrap();
+    let Err(err) = modtree::transform_mod_tree(src_dir) else {
+        panic!("cycle not detected");
+    };
+    assert!(
+        matches!(&err, Error::ModuleCycle { cycle } if cycle.len() == 2),
+        "{err}"
+    );
+}
+
+#[test]
+fn paths_outside_of_src_are_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let src_dir = camino::Utf8Path::from_path(dir.path()).unwrap();
+    fs_err::create_dir_all(src_dir.join("net")).unwrap();
+    fs_err::write(src_dir.join("lib.rs"), "pub mod net;\n").unwrap();
+    fs_err::write(src_dir.join("util.rs"), "pub fn f() {}\n").unwrap();
+
+    // going up is fine, as long as it stays in `src/`
+    fs_err::write(
+        src_dir.join("net/mod.rs"),
+        "#[path = \"../util.rs\"]\npub mod util;\n",
+    )
+    .unwrap();
+    modtree::transform_mod_tree(src_dir).unwrap();
+
+    let lib_rs = src_dir.join("lib.rs");
+    for path in ["../../mod-bar/src/lib.rs", lib_rs.as_str()] {
+        fs_err::write(
+            src_dir.join("net/mod.rs"),
+            format!("#[path = {path:?}]\npub mod util;\n"),
+        )
+        .unwrap();
+        let Err(err) = modtree::transform_mod_tree(src_dir) else {
+            panic!("{path} was accepted");
+        };
+        assert!(matches!(err, Error::ModulePath { .. }), "{err}");
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "src/net/mod.rs:1:10: `#[path]` must point inside of `src/`, `{path}` does not"
+            )
+        );
+    }
+}
+
+#[test]
+fn snapshot_lint_diagnostics() {
+    let input_rs = include_str!("testdata/non-dyn-compatible.rs");
+    let file = syn::parse_file(input_rs).unwrap();
+
+    let mut con_items = file.items.clone();
+    transform_ast(&mut con_items, &[], &mut Vec::new());
+
+    let output = lint::lint_exports(&file.items, &con_items)
+        .iter()
+        .map(|diagnostic| diagnostic.render("mod-foo/src/lib.rs".into(), input_rs))
+        .collect::<Vec<_>>()
+        .join("\n");
+    insta::assert_snapshot!(output);
+}
+
+/// The manifest of a `mod-foo` cdylib, with `extra` inserted after `[package]`.
+fn mod_manifest(extra: &str) -> String {
+    let extra = if extra.is_empty() {
+        String::new()
+    } else {
+        format!("{extra}\n")
+    };
+    format!(
+        "[package]\nname = \"mod-foo\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n{extra}[lib]\ncrate-type = [\"cdylib\"]\n\n[features]\ndefault = [\"impl\"]\nimpl = []\n"
+    )
+}
+
+#[test]
+fn generator_works_in_memory() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = camino::Utf8Path::from_path(dir.path()).unwrap();
+    let mod_path = root.join("mod-foo");
+    fs_err::create_dir_all(mod_path.join("src")).unwrap();
+    let manifest = mod_manifest("");
+    fs_err::write(mod_path.join("Cargo.toml"), &manifest).unwrap();
+    fs_err::write(
+        mod_path.join("src/lib.rs"),
+        "#[cfg(feature = \"impl\")]\nstruct ModImpl;\n\n#[dylo::export]\nimpl Mod for ModImpl {\n    fn hello(&self) -> u32 {\n        1\n    }\n}\n",
+    )
+    .unwrap();
+
+    let generator = Generator::new(root);
+    let mods = generator.mods(Scope::Workspace).unwrap();
+    let generated = generator.generate(&mods[0]).unwrap();
+    assert!(
+        generated
+            .con_files
+            .files
+            .contains_key(camino::Utf8Path::new("src/lib.rs"))
+    );
+    assert!(
+        generated
+            .mod_files
+            .files
+            .contains_key(camino::Utf8Path::new("src/.dylo/spec.rs"))
+    );
+    assert!(!root.join("foo").exists());
+
+    fs_err::write(mod_path.join("src/lib.rs"), "pub fn broken( {}\n").unwrap();
+    assert!(matches!(
+        generator.generate(&mods[0]),
+        Err(Error::Parse { .. })
+    ));
+
+    fs_err::write(mod_path.join("src/lib.rs"), "pub struct Fine;\n").unwrap();
+    fs_err::write(
+        mod_path.join("Cargo.toml"),
+        manifest.replace("cdylib", "rlib"),
+    )
+    .unwrap();
+    assert!(matches!(
+        generator.generate(&mods[0]),
+        Err(Error::Manifest { .. })
+    ));
+}
+
+#[test]
+fn build_script_consumer_is_a_single_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = camino::Utf8Path::from_path(dir.path()).unwrap();
+    let mod_path = root.join("mod-foo");
+    fs_err::create_dir_all(mod_path.join("src/net")).unwrap();
+    fs_err::write(
+        mod_path.join("Cargo.toml"),
+        mod_manifest("[package.metadata.dylo]\nbuild-script = true\n"),
+    )
+    .unwrap();
+    fs_err::write(
+        mod_path.join("src/lib.rs"),
+        "#![doc = include_str!(\"../README.md\")]\n\npub mod net;\n\n#[cfg(feature = \"impl\")]\nstruct ModImpl;\n\n#[dylo::export]\nimpl Mod for ModImpl {\n    fn hello(&self) -> u32 {\n        1\n    }\n}\n",
+    )
+    .unwrap();
+    fs_err::write(
+        mod_path.join("src/net/mod.rs"),
+        "//! Networking\n#![doc = include_str!(\"NET.md\")]\n\npub mod http;\n",
+    )
+    .unwrap();
+    fs_err::write(
+        mod_path.join("src/net/http.rs"),
+        "pub struct Request {\n    pub url: String,\n}\n",
+    )
+    .unwrap();
+
+    let generator = Generator::new(root);
+    let mods = generator.mods(Scope::Workspace).unwrap();
+    let generated = generator.generate(&mods[0]).unwrap();
+
+    let mut con_paths: Vec<_> = generated.con_files.files.keys().cloned().collect();
+    con_paths.sort();
+    assert_eq!(con_paths, ["Cargo.toml", "build.rs", "src/lib.rs"]);
+    let cargo_toml = generated.con_files.files[camino::Utf8Path::new("Cargo.toml")]
+        .replace(env!("CARGO_PKG_VERSION"), "$VERSION");
+    insta::assert_snapshot!(cargo_toml);
+    insta::assert_snapshot!(generated.con_files.files[camino::Utf8Path::new("src/lib.rs")]);
+    insta::assert_snapshot!(generated.con_files.files[camino::Utf8Path::new("build.rs")]);
+
+    let out_dir_files = generated.out_dir_files.unwrap();
+    let lib_rs =
+        out_dir_files.files[camino::Utf8Path::new("lib.rs")].replace(root.as_str(), "$ROOT");
+    insta::assert_snapshot!(lib_rs);
+    assert!(
+        out_dir_files
+            .files
+            .contains_key(camino::Utf8Path::new(".dylo/spec.rs"))
+    );
+}
+
+// runs the `--check` command too
+#[cfg(feature = "cli")]
+#[test]
+fn check_reports_out_of_date_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = camino::Utf8Path::from_path(dir.path()).unwrap();
+    let mod_path = root.join("mod-foo");
+    fs_err::create_dir_all(mod_path.join("src")).unwrap();
+    fs_err::write(mod_path.join("Cargo.toml"), mod_manifest("")).unwrap();
+    fs_err::write(
+        mod_path.join("src/lib.rs"),
+        "pub mod net;\n\n#[cfg(feature = \"impl\")]\nstruct ModImpl;\n\n#[dylo::export]\nimpl Mod for ModImpl {\n    fn hello(&self) -> u32 {\n        1\n    }\n}\n",
+    )
+    .unwrap();
+    fs_err::write(mod_path.join("src/net.rs"), "pub struct Request;\n").unwrap();
+    let check = || {
+        crate::command::run_command(
+            root.to_owned(),
+            types::DyloCommand::Check {
+                scope: Scope::Workspace,
+            },
+        )
+    };
+
+    let generator = Generator::new(root);
+    let mods = generator.mods(Scope::Workspace).unwrap();
+    let generated = generator.generate(&mods[0]).unwrap();
+    let mut missing = generated.out_of_date(&mods[0]).unwrap();
+    missing.sort();
+    assert_eq!(
+        missing,
+        [
+            "foo/Cargo.toml",
+            "foo/src/.dylo/spec.rs",
+            "foo/src/.dylo/support.rs",
+            "foo/src/lib.rs",
+            "foo/src/net.rs",
+            "mod-foo/src/.dylo/spec.rs",
+            "mod-foo/src/.dylo/support.rs",
+            "mod-foo/src/lib.rs",
+        ]
+        .map(|path| root.join(path))
+    );
+    assert!(check().is_err());
+
+    generated.commit(&mods[0]).unwrap();
+    assert!(generated.out_of_date(&mods[0]).unwrap().is_empty());
+    check().unwrap();
+
+    // contents are compared, whatever the timestamps
+    let con_lib_rs = root.join("foo/src/lib.rs");
+    let mut perms = fs_err::metadata(&con_lib_rs).unwrap().permissions();
+    std::os::unix::fs::PermissionsExt::set_mode(&mut perms, 0o644);
+    fs_err::set_permissions(&con_lib_rs, perms).unwrap();
+    fs_err::write(&con_lib_rs, "// edited\n").unwrap();
+    assert_eq!(generated.out_of_date(&mods[0]).unwrap(), [con_lib_rs]);
+    assert!(check().is_err());
+    generated.commit(&mods[0]).unwrap();
+
+    // generated files that would be removed are out of date too, other files aren't
+    fs_err::write(mod_path.join("src/lib.rs"), "#[cfg(feature = \"impl\")]\nstruct ModImpl;\n\n#[dylo::export]\nimpl Mod for ModImpl {\n    fn hello(&self) -> u32 {\n        1\n    }\n}\n").unwrap();
+    fs_err::remove_file(mod_path.join("src/net.rs")).unwrap();
+    fs_err::write(root.join("foo/src/notes.rs"), "// mine\n").unwrap();
+    // the mod's files are the user's, whatever they say, outside of `src/.dylo`
+    let user_files = [
+        "src/generated.rs",
+        "tests/generated.rs",
+        "vendor/src/lib.rs",
+    ]
+    .map(|path| mod_path.join(path));
+    for path in &user_files {
+        fs_err::create_dir_all(path.parent().unwrap()).unwrap();
+        fs_err::write(path, "// This file is automatically @generated by dylo\n").unwrap();
+    }
+    let generated = generator.generate(&mods[0]).unwrap();
+    let con_net_rs = root.join("foo/src/net.rs");
+    let out_of_date = generated.out_of_date(&mods[0]).unwrap();
+    assert!(out_of_date.contains(&con_net_rs), "{out_of_date:?}");
+    assert!(
+        !user_files.iter().any(|path| out_of_date.contains(path)),
+        "{out_of_date:?}"
+    );
+    assert!(check().is_err());
+    generated.commit(&mods[0]).unwrap();
+    assert!(!con_net_rs.exists());
+    for path in &user_files {
+        assert!(path.exists(), "{path}");
+    }
+    assert!(generated.out_of_date(&mods[0]).unwrap().is_empty());
+    check().unwrap();
+}
+
+#[test]
+fn switching_to_build_script_removes_stale_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = camino::Utf8Path::from_path(dir.path()).unwrap();
+    let mod_path = root.join("mod-foo");
+    fs_err::create_dir_all(mod_path.join("src")).unwrap();
+    fs_err::write(mod_path.join("Cargo.toml"), mod_manifest("")).unwrap();
+    fs_err::write(
+        mod_path.join("src/lib.rs"),
+        "pub mod net;\n\n#[cfg(feature = \"impl\")]\nstruct ModImpl;\n\n#[dylo::export]\nimpl Mod for ModImpl {\n    fn hello(&self) -> u32 {\n        1\n    }\n}\n",
+    )
+    .unwrap();
+    fs_err::write(mod_path.join("src/net.rs"), "pub struct Request;\n").unwrap();
+
+    let generator = Generator::new(root);
+    let mods = generator.mods(Scope::Workspace).unwrap();
+    generator
+        .generate(&mods[0])
+        .unwrap()
+        .commit(&mods[0])
+        .unwrap();
+    assert!(root.join("foo/src/net.rs").exists());
+    // not dylo's, or unreadable: left alone
+    fs_err::write(root.join("foo/src/notes.rs"), "// mine\n").unwrap();
+    fs_err::write(
+        root.join("foo/src/quoted.rs"),
+        "// mine, not \"// This file is automatically @generated by dylo\"\n// This file is automatically @generated by dylo\n",
+    )
+    .unwrap();
+    fs_err::write(root.join("foo/src/latin1.rs"), b"// \xe9t\xe9\n").unwrap();
+
+    fs_err::write(
+        mod_path.join("Cargo.toml"),
+        mod_manifest("[package.metadata.dylo]\nbuild-script = true\n"),
+    )
+    .unwrap();
+    let generated = generator.generate(&mods[0]).unwrap();
+    assert_eq!(
+        generated.con_files.stale_files(&mods[0].con_path).unwrap(),
+        ["src/.dylo/spec.rs", "src/.dylo/support.rs", "src/net.rs"]
+    );
+    generated.commit(&mods[0]).unwrap();
+    assert!(!root.join("foo/src/net.rs").exists());
+    assert!(!root.join("foo/src/.dylo/spec.rs").exists());
+    for path in ["notes.rs", "quoted.rs", "latin1.rs"] {
+        assert!(root.join("foo/src").join(path).exists(), "{path}");
+    }
+    assert!(root.join("foo/build.rs").exists());
+    assert!(!generated.con_files.is_different(&mods[0].con_path).unwrap());
+}
@@ -1,7 +1,7 @@
 use camino::Utf8PathBuf;
 use std::time::SystemTime;
 
-pub const DYLO_RUNTIME_VERSION: &str = "2.5.0";
+pub const DYLO_RUNTIME_VERSION: &str = "2.6.0";
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Scope {
@@ -28,6 +28,7 @@ pub struct ModInfo {
 }
 
 /// Reason we might have to regenerate a mod's consumer version.
+#[cfg(feature = "cli")]
 #[derive(Debug)]
 pub enum ProcessReason {
     Force,
@@ -35,10 +36,28 @@ pub enum ProcessReason {
     Modified,
 }
 
+/// How `dylo gen` verifies the consumers it regenerated.
+#[cfg(feature = "cli")]
+#[derive(Debug, Clone)]
+pub struct CheckOptions {
+    /// Whether to run a check at all
+    pub enabled: bool,
+    /// Command to run instead of `cargo check`, like `cargo clippy --all-targets`
+    pub command: Option<String>,
+    /// Also check the mods, with their `impl` feature enabled
+    pub impl_features: bool,
+}
+
+#[cfg(feature = "cli")]
 pub enum DyloCommand {
     Default {
         force: bool,
         scope: Scope,
+        check: CheckOptions,
+    },
+    /// Compares what would be generated with what's on disk, regardless of timestamps
+    Check {
+        scope: Scope,
     },
     List {
         scope: Scope,
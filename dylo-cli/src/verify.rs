@@ -0,0 +1,196 @@
+//! Verifies regenerated crates once `dylo gen` is done writing them: one cargo invocation
+//! covers every consumer that changed, and their mods with `--check-impl`.
+
+use std::{
+    io::{BufRead as _, BufReader},
+    process::{Command, Stdio},
+};
+
+use camino::Utf8Path;
+
+use crate::{diagnostics::SourceMap, types::CheckOptions};
+
+/// Checks the consumers of `mods` (names without the `mod-` prefix) as configured by
+/// `options`. Returns whether the check passed.
+pub(crate) fn verify(
+    workspace_root: &Utf8Path,
+    mods: &[String],
+    options: &CheckOptions,
+    source_map: &SourceMap,
+) -> eyre::Result<bool> {
+    let command_line = options.command.as_deref().unwrap_or("cargo check");
+    let (program, args) = check_args(command_line, mods, options.impl_features)?;
+    let mut command = Command::new(program);
+    command.args(args).current_dir(workspace_root);
+
+    tracing::info!("🔨 Running `{command_line}` for {}", mods.join(", "));
+    let start = std::time::Instant::now();
+    let passed = run_mapped(&mut command, source_map)?;
+
+    let duration = start.elapsed();
+    if passed {
+        tracing::info!("✅ Check passed in {:.2}s", duration.as_secs_f32());
+    } else {
+        tracing::error!("❌ Check failed in {:.2}s", duration.as_secs_f32());
+    }
+    Ok(passed)
+}
+
+/// Splits `command_line` like a shell would, and adds the `--package`, `--features` and
+/// `--message-format` flags cargo needs. They go before any `--`, past which arguments
+/// are for rustc or clippy (as in `cargo clippy -- -D warnings`).
+fn check_args(
+    command_line: &str,
+    mods: &[String],
+    impl_features: bool,
+) -> eyre::Result<(String, Vec<String>)> {
+    let Some(mut words) = shlex::split(command_line) else {
+        eyre::bail!("The check command has unbalanced quotes: {command_line}");
+    };
+    if words.is_empty() {
+        eyre::bail!("The check command is empty");
+    }
+    let program = words.remove(0);
+
+    let mut flags = Vec::new();
+    for name in mods {
+        flags.push("--package".to_owned());
+        flags.push(name.clone());
+    }
+    if impl_features {
+        for name in mods {
+            flags.push("--package".to_owned());
+            flags.push(format!("mod-{name}"));
+        }
+        let features: Vec<String> = mods.iter().map(|name| format!("mod-{name}/impl")).collect();
+        flags.push("--features".to_owned());
+        flags.push(features.join(","));
+    }
+    flags.push("--message-format=json".to_owned());
+
+    let at = words
+        .iter()
+        .position(|word| word == "--")
+        .unwrap_or(words.len());
+    words.splice(at..at, flags);
+    Ok((program, words))
+}
+
+/// Runs a cargo `command` (`check`, `clippy`...) that already has `--message-format=json`,
+/// printing diagnostics with locations in generated files mapped back to their sources.
+/// Returns whether the command succeeded.
+fn run_mapped(command: &mut Command, source_map: &SourceMap) -> std::io::Result<bool> {
+    let mut child = command.stdout(Stdio::piped()).spawn()?;
+
+    let stdout = child.stdout.take().expect("stdout is piped");
+    for line in BufReader::new(stdout).lines() {
+        let line = line?;
+        let Ok(message) = serde_json::from_str::<serde_json::Value>(&line) else {
+            println!("{line}");
+            continue;
+        };
+        if message["reason"] != "compiler-message" {
+            continue;
+        }
+        if let Some(rendered) = message["message"]["rendered"].as_str() {
+            eprint!("{}", source_map.rewrite(rendered));
+        }
+    }
+    Ok(child.wait()?.success())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(command_line: &str, impl_features: bool) -> Vec<String> {
+        let mods = ["foo".to_owned(), "bar".to_owned()];
+        let (program, args) = check_args(command_line, &mods, impl_features).unwrap();
+        std::iter::once(program).chain(args).collect()
+    }
+
+    #[test]
+    fn checks_consumers() {
+        assert_eq!(
+            args("cargo check", false),
+            [
+                "cargo",
+                "check",
+                "--package",
+                "foo",
+                "--package",
+                "bar",
+                "--message-format=json"
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_mods_with_their_impl_feature() {
+        assert_eq!(
+            args("cargo check", true),
+            [
+                "cargo",
+                "check",
+                "--package",
+                "foo",
+                "--package",
+                "bar",
+                "--package",
+                "mod-foo",
+                "--package",
+                "mod-bar",
+                "--features",
+                "mod-foo/impl,mod-bar/impl",
+                "--message-format=json"
+            ]
+        );
+    }
+
+    #[test]
+    fn adds_flags_before_double_dash() {
+        assert_eq!(
+            args("cargo clippy --all-targets -- -D warnings", false),
+            [
+                "cargo",
+                "clippy",
+                "--all-targets",
+                "--package",
+                "foo",
+                "--package",
+                "bar",
+                "--message-format=json",
+                "--",
+                "-D",
+                "warnings"
+            ]
+        );
+    }
+
+    #[test]
+    fn splits_like_a_shell() {
+        assert_eq!(
+            args(
+                r#"cargo "my check" --config 'build.rustflags = ["-W", "x"]'"#,
+                false
+            )[..4],
+            [
+                "cargo",
+                "my check",
+                "--config",
+                r#"build.rustflags = ["-W", "x"]"#
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_command_lines() {
+        let mods = ["foo".to_owned()];
+        for command_line in ["", "   ", "cargo 'check"] {
+            assert!(
+                check_args(command_line, &mods, false).is_err(),
+                "{command_line:?}"
+            );
+        }
+    }
+}
@@ -1,12 +1,19 @@
-use std::{collections::HashMap, time::SystemTime};
+use std::{
+    collections::HashMap,
+    io::{BufRead as _, BufReader},
+    time::SystemTime,
+};
 
 use camino::{Utf8Path, Utf8PathBuf};
 use tracing::debug;
 
-use crate::types::{ModInfo, Scope};
+use crate::{
+    GENERATED_HEADER,
+    types::{ModInfo, Scope},
+};
 
 /// Lists all mods for a given scope
-pub fn list_mods(workspace_root: &camino::Utf8Path, scope: Scope) -> eyre::Result<Vec<ModInfo>> {
+pub fn list_mods(workspace_root: &camino::Utf8Path, scope: Scope) -> std::io::Result<Vec<ModInfo>> {
     let mut mods = Vec::new();
     for entry in walkdir::WalkDir::new(workspace_root) {
         let entry = entry?;
@@ -56,6 +63,7 @@ pub fn list_mods(workspace_root: &camino::Utf8Path, scope: Scope) -> eyre::Resul
     Ok(mods)
 }
 
+#[cfg(feature = "cli")]
 pub fn get_single_mod(workspace_root: &camino::Utf8Path, scope: Scope) -> eyre::Result<ModInfo> {
     match scope {
         Scope::Workspace => {
@@ -130,17 +138,27 @@ pub fn get_latest_timestamp(path: &camino::Utf8Path) -> std::io::Result<SystemTi
 #[derive(Debug, Clone)]
 pub struct FileSet {
     pub files: HashMap<Utf8PathBuf, String>,
+    /// Directories (or files) that only dylo writes to, relative to the root: where
+    /// stale files are looked for, see [`Self::stale_files`]
+    pub owned: Vec<Utf8PathBuf>,
 }
 
 impl FileSet {
     pub fn new() -> Self {
         Self {
             files: HashMap::new(),
+            owned: Vec::new(),
         }
     }
 
-    /// True if any files are missing from disk or have different contents.
+    /// True if any files are missing from disk or have different contents, or if there are
+    /// stale ones, see [`Self::stale_files`].
     pub fn is_different(&self, root: &Utf8Path) -> std::io::Result<bool> {
+        Ok(!self.different_files(root)?.is_empty() || !self.stale_files(root)?.is_empty())
+    }
+
+    /// Files that are missing from disk or have different contents, sorted.
+    pub fn different_files(&self, root: &Utf8Path) -> std::io::Result<Vec<Utf8PathBuf>> {
         debug!(
             "Checking {count} files for differences in {root}",
             count = self.files.len(),
@@ -148,12 +166,14 @@ impl FileSet {
         );
         let mut missing_count = 0;
         let mut changed_count = 0;
+        let mut different = Vec::new();
 
         for (rel_path, contents) in &self.files {
             let full_path = root.join(rel_path);
             if !full_path.exists() {
                 debug!("File missing: {full_path}");
                 missing_count += 1;
+                different.push(rel_path.clone());
                 continue;
             }
 
@@ -161,10 +181,10 @@ impl FileSet {
             if &disk_contents != contents {
                 debug!("File content different: {full_path}");
                 changed_count += 1;
+                different.push(rel_path.clone());
             }
         }
 
-        let is_diff = missing_count > 0 || changed_count > 0;
         debug!(
             "Found {total} differences: {missing} missing, {changed} changed in {root}",
             total = missing_count + changed_count,
@@ -173,10 +193,43 @@ impl FileSet {
             root = root
         );
 
-        Ok(is_diff)
+        different.sort();
+        Ok(different)
+    }
+
+    /// Files in the owned paths under `root` that dylo generated (they start with its
+    /// header) but that aren't part of this set, sorted: leftovers of modules that were
+    /// removed, or of a consumer that's now generated by its build script. Files that
+    /// can't be read are left alone.
+    pub fn stale_files(&self, root: &Utf8Path) -> std::io::Result<Vec<Utf8PathBuf>> {
+        let mut stale = Vec::new();
+        for owned in &self.owned {
+            for entry in walkdir::WalkDir::new(root.join(owned))
+                .into_iter()
+                .flatten()
+            {
+                let Some(path) = Utf8Path::from_path(entry.path()) else {
+                    continue;
+                };
+                if !entry.file_type().is_file() || path.extension() != Some("rs") {
+                    continue;
+                }
+                let rel_path = path.strip_prefix(root).unwrap_or(path);
+                if self.files.contains_key(rel_path) || !is_generated(path) {
+                    continue;
+                }
+                debug!("Stale file: {path}");
+                stale.push(rel_path.to_owned());
+            }
+        }
+
+        stale.sort();
+        stale.dedup();
+        Ok(stale)
     }
 
-    /// Write file contents to disk, creating parent directories as needed.
+    /// Write file contents to disk, creating parent directories as needed, and remove
+    /// stale files (see [`Self::stale_files`]).
     pub fn commit(&self, root: &Utf8Path) -> std::io::Result<()> {
         use std::fs;
         use std::os::unix::fs::PermissionsExt;
@@ -211,10 +264,31 @@ impl FileSet {
             perms.set_mode(mode & !0o222);
             fs::set_permissions(&full_path, perms)?;
         }
+
+        for rel_path in self.stale_files(root)? {
+            let full_path = root.join(rel_path);
+            debug!("Removing stale file {full_path}");
+            fs_err::remove_file(&full_path)?;
+        }
         Ok(())
     }
 }
 
+/// Whether the first line of the file at `path` is [`GENERATED_HEADER`].
+fn is_generated(path: &Utf8Path) -> bool {
+    let Ok(file) = fs_err::File::open(path) else {
+        return false;
+    };
+    let mut first_line = Vec::new();
+    if BufReader::new(file)
+        .read_until(b'\n', &mut first_line)
+        .is_err()
+    {
+        return false;
+    }
+    first_line.trim_ascii_end() == GENERATED_HEADER.as_bytes()
+}
+
 impl Default for FileSet {
     fn default() -> Self {
         Self::new()
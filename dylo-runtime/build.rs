@@ -0,0 +1,23 @@
+// Records bits of the build configuration that `details::BuildConfig` can't
+// observe with `cfg!` alone, so that hosts and modules can compare them at load time.
+
+fn main() {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = std::process::Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=DYLO_RUSTC_VERSION={rustc_version}");
+
+    // there's no way to find out which `#[global_allocator]` the final binary
+    // uses from here, so builds that use a custom one are expected to say so,
+    // typically through the `[env]` section of `.cargo/config.toml`.
+    let allocator = std::env::var("DYLO_ALLOCATOR").unwrap_or_else(|_| "system".to_string());
+    println!("cargo:rustc-env=DYLO_ALLOCATOR={allocator}");
+    println!("cargo:rerun-if-env-changed=DYLO_ALLOCATOR");
+    println!("cargo:rerun-if-env-changed=RUSTC");
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -1,10 +1,13 @@
 use std::collections::HashMap;
-use std::ffi::CString;
-use std::path::PathBuf;
-use std::sync::{Arc, LazyLock, Mutex};
+use std::ffi::{CStr, CString};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex, Once};
 use std::time::Instant;
 
-use platform::{Extensions, RTLD_LAZY, blue, dlerror, dlopen, dlsym};
+use build_config::{BUILD_CONFIG_SYMBOL, check_module_record};
+use config::Config;
+use platform::{Extensions, RTLD_GLOBAL, RTLD_NOW, blue, dlerror, dlopen, dlsym};
+use slot::INTERFACE_SYMBOL;
 
 // dummy trait just so we can make fat pointers
 pub trait AnyMod: Send + Sync + 'static {}
@@ -14,45 +17,86 @@ pub trait AnyMod: Send + Sync + 'static {}
 // the mod's vtable as well.
 pub type AnyModRef = &'static dyn AnyMod;
 
-static DYLO_DEBUG: LazyLock<bool> =
-    LazyLock::new(|| matches!(std::env::var("DYLO_DEBUG").as_deref(), Ok("1")));
+// `$DYLO_DEBUG` wins over the `debug` key of `dylo.toml`
+static DYLO_DEBUG: LazyLock<bool> = LazyLock::new(|| match std::env::var("DYLO_DEBUG") {
+    Ok(value) => value == "1",
+    Err(_) => Config::get().debug,
+});
 
 macro_rules! debug {
     ($($arg:tt)*) => {
-        if *DYLO_DEBUG {
+        if *$crate::details::DYLO_DEBUG {
             eprintln!($($arg)*);
         }
     };
 }
 
+mod build_config;
+mod config;
+#[cfg(feature = "isolation")]
+pub mod isolation;
 mod platform;
+mod slot;
+
+pub use build_config::build_config_record;
+pub use slot::{Interface, ModSlot, load_typed};
+
+/// Directory containing the current executable, after resolving symlinks.
+///
+/// Unlike [`SearchPaths::new`], this doesn't log anything, since it's used
+/// while loading the config (which determines whether we log at all).
+#[cfg(feature = "config")]
+fn current_exe_dir() -> Option<PathBuf> {
+    let exe_path = std::env::current_exe().ok()?;
+    let real_exe_path = exe_path.canonicalize().unwrap_or(exe_path);
+    real_exe_path.parent().map(|p| p.to_path_buf())
+}
 
 struct SearchPaths {
+    /// `$DYLO_MOD_DIR`, searched before anything `dylo.toml` says
+    mod_dir: Option<PathBuf>,
     paths: Vec<PathBuf>,
 }
 
 impl SearchPaths {
-    fn from_env() -> Self {
+    fn new(config: &Config) -> Self {
         let mut paths = Vec::new();
 
         debug!("dylo search paths:");
-        if let Ok(dir) = std::env::var("DYLO_MOD_DIR") {
-            let path = PathBuf::from(dir);
-            if !path.is_absolute() {
-                panic!(
-                    "$DYLO_MOD_DIR must be an absolute path, refusing to proceed. (DYLO_MOD_DIR was set to {})",
-                    blue(path.display())
-                );
+        let mod_dir = match std::env::var("DYLO_MOD_DIR") {
+            Ok(dir) => {
+                let path = PathBuf::from(dir);
+                if !path.is_absolute() {
+                    panic!(
+                        "$DYLO_MOD_DIR must be an absolute path, refusing to proceed. (DYLO_MOD_DIR was set to {})",
+                        blue(path.display())
+                    );
+                }
+                if !path.exists() {
+                    panic!(
+                        "$DYLO_MOD_DIR must exist. (DYLO_MOD_DIR was set to {})",
+                        blue(path.display())
+                    );
+                }
+                debug!("  {} (from $DYLO_MOD_DIR)", path.display());
+                Some(path)
             }
-            if !path.exists() {
-                panic!(
-                    "$DYLO_MOD_DIR must exist. (DYLO_MOD_DIR was set to {})",
-                    blue(path.display())
-                );
+            Err(_) => {
+                debug!("(note: you can set $DYLO_MOD_DIR to prepend your own search path)");
+                None
             }
-            paths.push(path);
-        } else {
-            debug!("(note: you can set $DYLO_MOD_DIR to prepend your own search path)");
+        };
+
+        for path in &config.search_paths {
+            paths.push(config.resolve(path));
+        }
+
+        if config.strict {
+            debug!("(strict mode: skipping executable-relative search paths)");
+            for path in &paths {
+                debug!("  {}", path.display());
+            }
+            return Self { mod_dir, paths };
         }
 
         let exe_path = std::env::current_exe().unwrap_or_else(|e| {
@@ -101,28 +145,177 @@ impl SearchPaths {
             debug!("  {}", path.display());
         }
 
-        Self { paths }
+        Self { mod_dir, paths }
     }
 
-    fn find_module(&self, mod_name: &str) -> Option<PathBuf> {
+    /// Looks for a module in `$DYLO_MOD_DIR`, then at the path `dylo.toml` sets for it,
+    /// then in every other search path, in order. Within a search path, versioned files
+    /// (`libmod_foo-1.4.2.so`) that are semver-compatible with `version` win over the
+    /// unversioned `libmod_foo.so`, which stays a fallback.
+    ///
+    /// Returns the module's path, and its version if it's a versioned file.
+    fn find_module(
+        &self,
+        config: &Config,
+        mod_name: &str,
+        version: Option<&semver::Version>,
+    ) -> Option<(PathBuf, Option<semver::Version>)> {
+        let req = version.map(compatible_with);
+
+        if let Some(found) = self
+            .mod_dir
+            .as_deref()
+            .and_then(|dir| Self::find_in(dir, mod_name, req.as_ref()))
+        {
+            return Some(found);
+        }
+
+        if let Some(path) = config.mods.get(mod_name).and_then(|m| m.path.as_ref()) {
+            let path = config.resolve(path);
+            if !path.exists() {
+                panic!(
+                    "dylo.toml sets the path of module {} to {}, which does not exist",
+                    blue(mod_name),
+                    blue(path.display())
+                );
+            }
+            debug!("Using configured path for module: {}", blue(path.display()));
+            return Some((path, None));
+        }
+
+        let found = self
+            .paths
+            .iter()
+            .find_map(|dir| Self::find_in(dir, mod_name, req.as_ref()));
+        if found.is_none() {
+            debug!("Module not found: {}", blue(mod_name));
+        }
+        found
+    }
+
+    /// Looks for a module in a single search path, see [`Self::find_module`].
+    fn find_in(
+        dir: &Path,
+        mod_name: &str,
+        req: Option<&semver::VersionReq>,
+    ) -> Option<(PathBuf, Option<semver::Version>)> {
         let extensions = Extensions::get();
-        let file_name = format!("libmod_{}.{}", mod_name, extensions.lib);
-
-        for path in &self.paths {
-            let full_path = path.join(&file_name);
-            debug!("Looking for module in: {}", blue(full_path.display()));
-            if full_path.exists() {
-                debug!("Found module at: {}", blue(full_path.display()));
-                return Some(full_path);
+        if let Some(req) = req {
+            if let Some((version, full_path)) =
+                Self::find_versioned(dir, mod_name, extensions.lib, req)
+            {
+                debug!(
+                    "Found module version {} (compatible with {}) at: {}",
+                    blue(&version),
+                    blue(req),
+                    blue(full_path.display())
+                );
+                return Some((full_path, Some(version)));
             }
         }
 
-        debug!("Module not found: {}", blue(mod_name));
+        let full_path = dir.join(format!("libmod_{}.{}", mod_name, extensions.lib));
+        debug!("Looking for module in: {}", blue(full_path.display()));
+        if full_path.exists() {
+            debug!("Found module at: {}", blue(full_path.display()));
+            return Some((full_path, None));
+        }
         None
     }
+
+    /// Returns the highest version of `libmod_{mod_name}-{version}.{ext}` in `dir`
+    /// that matches `req`, if any.
+    fn find_versioned(
+        dir: &Path,
+        mod_name: &str,
+        ext: &str,
+        req: &semver::VersionReq,
+    ) -> Option<(semver::Version, PathBuf)> {
+        let prefix = format!("libmod_{mod_name}-");
+        let suffix = format!(".{ext}");
+
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                debug!("Could not list {}: {e}", blue(dir.display()));
+                return None;
+            }
+        };
+
+        entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let file_name = entry.file_name();
+                let version = file_name
+                    .to_str()?
+                    .strip_prefix(&prefix)?
+                    .strip_suffix(&suffix)?;
+                let version = semver::Version::parse(version).ok()?;
+                req.matches(&version).then(|| (version, entry.path()))
+            })
+            .max_by(|(a, _), (b, _)| a.cmp(b))
+    }
 }
 
-type LockSlot = Arc<Mutex<Option<AnyModRef>>>;
+/// In strict mode, refuses to load anything that could have been tampered
+/// with by another user: the file itself and its parent directory must not be
+/// writable by group or others.
+fn check_permissions(config: &Config, path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if !config.strict {
+        return;
+    }
+
+    let candidates = [Some(path), path.parent()];
+    for candidate in candidates.into_iter().flatten() {
+        let mode = std::fs::metadata(candidate)
+            .unwrap_or_else(|e| panic!("Could not stat {}: {e}", blue(candidate.display())))
+            .permissions()
+            .mode();
+        if mode & 0o022 != 0 {
+            panic!(
+                "dylo is in strict mode and {} is writable by group or others (mode {:o}), refusing to load it",
+                blue(candidate.display()),
+                mode & 0o777
+            );
+        }
+    }
+}
+
+/// `dlopen`s the libraries listed under `preload` in `dylo.toml`, once.
+fn preload_libraries(config: &Config) {
+    static PRELOAD: Once = Once::new();
+    PRELOAD.call_once(|| {
+        for path in &config.preload {
+            let path = config.resolve(path);
+            check_permissions(config, &path);
+            debug!("Preloading {}", blue(path.display()));
+
+            let c_path = CString::new(path.to_str().unwrap()).expect("Invalid path");
+            let handle = unsafe { dlopen(c_path.as_ptr() as *const _, RTLD_NOW | RTLD_GLOBAL) };
+            if handle.is_null() {
+                let err = unsafe { std::ffi::CStr::from_ptr(dlerror() as *const _) }
+                    .to_string_lossy()
+                    .into_owned();
+                panic!("Failed to preload {}: {}", blue(path.display()), err);
+            }
+        }
+    });
+}
+
+/// A module that was loaded, along with what it must be checked against every
+/// time it's asked for again.
+#[derive(Clone)]
+pub(crate) struct Loaded {
+    pub module: AnyModRef,
+    /// Identity of the module's `Mod` trait, if it exports one, see [`Interface`]
+    pub interface_id: Option<&'static CStr>,
+    /// Version of the module, if it was loaded from a versioned file
+    pub version: Option<semver::Version>,
+}
+
+type LockSlot = Arc<Mutex<Option<Loaded>>>;
 
 // keep locks per module name, exported by rubicon.
 rubicon::process_local! {
@@ -131,25 +324,154 @@ rubicon::process_local! {
 }
 
 pub fn load_mod(mod_name: &'static str) -> AnyModRef {
+    load(mod_name, None, None)
+}
+
+/// Like [`load_mod`], but prefers versioned module files (`libmod_foo-1.4.2.so`)
+/// that are semver-compatible with `version`, which is typically the consumer's
+/// `CARGO_PKG_VERSION`.
+pub fn load_mod_versioned(mod_name: &'static str, version: &str) -> AnyModRef {
+    let version = parse_version(mod_name, version);
+    load(mod_name, Some(&version), None)
+}
+
+/// What versions of a module a consumer at `version` accepts.
+fn compatible_with(version: &semver::Version) -> semver::VersionReq {
+    semver::VersionReq::parse(&format!("^{version}")).expect("caret requirement is always valid")
+}
+
+fn parse_version(mod_name: &str, version: &str) -> semver::Version {
+    semver::Version::parse(version).unwrap_or_else(|e| {
+        panic!(
+            "Invalid version {} for module {}: {e}",
+            blue(version),
+            blue(mod_name)
+        )
+    })
+}
+
+/// Loads (or returns the already-loaded) module `mod_name`. If `interface_id` is
+/// set, the module's `Mod` trait must have that identity, see [`Interface`].
+fn load(
+    mod_name: &str,
+    version: Option<&semver::Version>,
+    interface_id: Option<&CStr>,
+) -> AnyModRef {
+    let loaded = load_or_init(mod_name, || {
+        let before_load = Instant::now();
+        let (handle, resolved_version) = open_module(mod_name, version);
+
+        let symbol_name = CString::new(INTERFACE_SYMBOL).unwrap();
+        let interface_sym = unsafe { dlsym(handle, symbol_name.as_ptr() as *const _) };
+        let interface_id = if interface_sym.is_null() {
+            None
+        } else {
+            type InterfaceFn = unsafe extern "C" fn() -> *const std::ffi::c_char;
+            let interface_fn: InterfaceFn = unsafe { std::mem::transmute(interface_sym) };
+            // modules are never unloaded, so their statics live forever
+            Some(unsafe { CStr::from_ptr(interface_fn()) })
+        };
+
+        let symbol_name = CString::new("github.com_bearcove_dylo").unwrap();
+        let init_sym = unsafe { dlsym(handle, symbol_name.as_ptr() as *const _) };
+        if init_sym.is_null() {
+            let err = unsafe { std::ffi::CStr::from_ptr(dlerror() as *const _) }
+                .to_string_lossy()
+                .into_owned();
+            panic!("Did not find in dynamic library: {}", err);
+        }
+
+        type InitFn = unsafe extern "Rust" fn() -> AnyModRef;
+        let init_fn: InitFn = unsafe { std::mem::transmute(init_sym) };
+        let plugin = unsafe { init_fn() };
+
+        debug!(
+            "📦 Loaded {} in {:?}",
+            blue(mod_name),
+            before_load.elapsed()
+        );
+        Loaded {
+            module: plugin,
+            interface_id,
+            version: resolved_version,
+        }
+    });
+    check_loaded(mod_name, &loaded, version, interface_id)
+}
+
+/// Returns the module cached under `mod_name`, or calls `init` to load it,
+/// holding that module's lock so it only ever happens once.
+pub(crate) fn load_or_init(mod_name: &str, init: impl FnOnce() -> Loaded) -> Loaded {
     let slot = {
         let mut locks = LOCKS.lock().unwrap();
         locks.entry(mod_name.to_string()).or_default().clone()
     };
     let mut locked_slot = slot.lock().unwrap();
-    if let Some(fat_pointer) = locked_slot.as_ref() {
+    if let Some(loaded) = locked_slot.as_ref() {
         // if we've already loaded the mod, return the same address
-        return *fat_pointer;
+        return loaded.clone();
     }
 
-    let search_paths = SearchPaths::from_env();
-    let dylib_path = search_paths
-        .find_module(mod_name)
-        .unwrap_or_else(|| panic!("dylo could not find find module: {}", mod_name));
+    let loaded = init();
+    *locked_slot = Some(loaded.clone());
+    loaded
+}
+
+/// Checks a (possibly cached) module against what this caller expects: whoever
+/// loaded it first may have asked for another version, or another interface.
+pub(crate) fn check_loaded(
+    mod_name: &str,
+    loaded: &Loaded,
+    version: Option<&semver::Version>,
+    interface_id: Option<&CStr>,
+) -> AnyModRef {
+    if let (Some(requested), Some(resolved)) = (version, &loaded.version) {
+        if !compatible_with(requested).matches(resolved) {
+            panic!(
+                "Module {} is already loaded at version {}, which is not compatible with {} requested here",
+                blue(mod_name),
+                blue(resolved),
+                blue(requested)
+            );
+        }
+    }
 
-    let before_load = Instant::now();
+    if let Some(expected_id) = interface_id {
+        match loaded.interface_id {
+            None => debug!(
+                "{} does not export its interface identity (generated by an older dylo-cli?), skipping checks",
+                blue(mod_name)
+            ),
+            Some(actual_id) if actual_id != expected_id => panic!(
+                "Module {} implements {}, but this consumer expects {}: the consumer and the module were generated from different versions of the spec. Run `dylo gen` and rebuild both.",
+                blue(mod_name),
+                blue(actual_id.to_string_lossy()),
+                blue(expected_id.to_string_lossy())
+            ),
+            Some(_) => {}
+        }
+    }
+
+    loaded.module
+}
+
+/// Finds a module, `dlopen`s it and checks its build configuration against ours.
+/// Returns its handle, and its version if it was loaded from a versioned file.
+fn open_module(
+    mod_name: &str,
+    version: Option<&semver::Version>,
+) -> (*mut std::ffi::c_void, Option<semver::Version>) {
+    let config = Config::get();
+    let search_paths = SearchPaths::new(config);
+    let (dylib_path, resolved_version) = search_paths
+        .find_module(config, mod_name, version)
+        .unwrap_or_else(|| panic!("dylo could not find find module: {}", mod_name));
+    check_permissions(config, &dylib_path);
+    preload_libraries(config);
 
     let dylib_path = CString::new(dylib_path.to_str().unwrap()).expect("Invalid path");
-    let handle = unsafe { dlopen(dylib_path.as_ptr() as *const _, RTLD_LAZY) };
+    let flags = config.dlopen_flags(mod_name);
+    let handle = unsafe { dlopen(dylib_path.as_ptr() as *const _, flags) };
     if handle.is_null() {
         let err = unsafe { std::ffi::CStr::from_ptr(dlerror() as *const _) }
             .to_string_lossy()
@@ -159,25 +481,147 @@ pub fn load_mod(mod_name: &'static str) -> AnyModRef {
 
     // note: we never dlclose the handle, on purpose.
 
-    let symbol_name = CString::new("github.com_bearcove_dylo").unwrap();
-    let init_sym = unsafe { dlsym(handle, symbol_name.as_ptr() as *const _) };
-    if init_sym.is_null() {
-        let err = unsafe { std::ffi::CStr::from_ptr(dlerror() as *const _) }
-            .to_string_lossy()
-            .into_owned();
-        panic!("Did not find in dynamic library: {}", err);
+    let symbol_name = CString::new(BUILD_CONFIG_SYMBOL).unwrap();
+    let build_config_sym = unsafe { dlsym(handle, symbol_name.as_ptr() as *const _) };
+    if build_config_sym.is_null() {
+        debug!(
+            "{} does not export a build configuration record (generated by an older dylo-cli?), skipping checks",
+            blue(mod_name)
+        );
+    } else {
+        type BuildConfigFn = unsafe extern "C" fn() -> *const std::ffi::c_char;
+        let build_config_fn: BuildConfigFn = unsafe { std::mem::transmute(build_config_sym) };
+        let record = unsafe { std::ffi::CStr::from_ptr(build_config_fn()) };
+        check_module_record(mod_name, record);
     }
 
-    type InitFn = unsafe extern "Rust" fn() -> AnyModRef;
-    let init_fn: InitFn = unsafe { std::mem::transmute(init_sym) };
-    let plugin = unsafe { init_fn() };
+    (handle, resolved_version)
+}
 
-    debug!(
-        "📦 Loaded {} in {:?}",
-        blue(mod_name),
-        before_load.elapsed()
-    );
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    *locked_slot = Some(plugin);
-    plugin
+    #[test]
+    fn find_versioned_picks_highest_compatible() {
+        let dir = std::env::temp_dir().join(format!("dylo-versioned-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "libmod_markdown-1.3.0.so",
+            "libmod_markdown-1.4.2.so",
+            "libmod_markdown-2.0.0.so",
+            "libmod_markdown-notaversion.so",
+            "libmod_markdown.so",
+            "libmod_markdown_extra-1.9.0.so",
+        ] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+
+        let find = |req: &str| {
+            let req = semver::VersionReq::parse(req).unwrap();
+            SearchPaths::find_versioned(&dir, "markdown", "so", &req).map(|(v, _)| v.to_string())
+        };
+        assert_eq!(find("^1.4.0").as_deref(), Some("1.4.2"));
+        assert_eq!(find("^1.0.0").as_deref(), Some("1.4.2"));
+        assert_eq!(find("^2.0.0").as_deref(), Some("2.0.0"));
+        assert_eq!(find("^3.0.0"), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mod_dir_wins_over_configured_paths() {
+        let root = std::env::temp_dir().join(format!("dylo-precedence-{}", std::process::id()));
+        let (mod_dir, configured) = (root.join("mod-dir"), root.join("configured"));
+        std::fs::create_dir_all(&mod_dir).unwrap();
+        std::fs::create_dir_all(&configured).unwrap();
+        let file_name = format!("libmod_markdown.{}", Extensions::get().lib);
+        let configured_path = configured.join(&file_name);
+        std::fs::write(&configured_path, "").unwrap();
+
+        let mut config = Config::default();
+        config.mods.insert(
+            "markdown".to_string(),
+            config::ModConfig {
+                path: Some(configured_path.clone()),
+                ..Default::default()
+            },
+        );
+        let search_paths = SearchPaths {
+            mod_dir: Some(mod_dir.clone()),
+            paths: Vec::new(),
+        };
+        let find = || {
+            search_paths
+                .find_module(&config, "markdown", None)
+                .unwrap()
+                .0
+        };
+
+        // not in `$DYLO_MOD_DIR`: `dylo.toml`'s path it is
+        assert_eq!(find(), configured_path);
+        std::fs::write(mod_dir.join(&file_name), "").unwrap();
+        assert_eq!(find(), mod_dir.join(&file_name));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    trait Markdown: Send + Sync {}
+    trait Html: Send + Sync {}
+    struct Impl;
+    impl AnyMod for Impl {}
+    impl Markdown for Impl {}
+    impl Html for Impl {}
+    unsafe impl Interface for dyn Markdown {
+        const ID: &'static CStr = c"Markdown<v1>";
+    }
+    unsafe impl Interface for dyn Html {
+        const ID: &'static CStr = c"Html<v1>";
+    }
+
+    /// Caches `Impl` under `mod_name`, as if it had been loaded from a module
+    /// implementing `interface_id`, at `version`.
+    fn preload(mod_name: &str, interface_id: &'static CStr, version: Option<&str>) {
+        load_or_init(mod_name, || Loaded {
+            module: &Impl,
+            interface_id: Some(interface_id),
+            version: version.map(|v| semver::Version::parse(v).unwrap()),
+        });
+    }
+
+    #[test]
+    fn cached_modules_are_checked_again() {
+        preload("slot-same", c"Markdown<v1>", None);
+        load_typed::<dyn Markdown>("slot-same", None);
+        load_typed::<dyn Markdown>("slot-same", None);
+    }
+
+    #[test]
+    #[should_panic(expected = "generated from different versions of the spec")]
+    fn cached_modules_with_another_interface_are_rejected() {
+        preload("slot-other", c"Markdown<v1>", None);
+        load_typed::<dyn Markdown>("slot-other", None);
+        load_typed::<dyn Html>("slot-other", None);
+    }
+
+    #[test]
+    fn cached_modules_are_version_checked() {
+        preload("slot-versioned", c"Markdown<v1>", Some("1.4.2"));
+        load_mod_versioned("slot-versioned", "1.3.0");
+        load_typed::<dyn Markdown>("slot-versioned", Some("1.4.0"));
+
+        let incompatible = std::panic::catch_unwind(|| {
+            load_mod_versioned("slot-versioned", "2.0.0");
+        });
+        assert!(incompatible.is_err());
+    }
+
+    #[test]
+    fn build_config_record_roundtrip() {
+        use build_config::BuildConfig;
+
+        let record = unsafe { std::ffi::CStr::from_ptr(build_config_record()) };
+        let parsed = BuildConfig::from_record(record.to_str().unwrap()).unwrap();
+        assert_eq!(parsed, BuildConfig::current());
+    }
 }
@@ -0,0 +1,181 @@
+use std::ffi::{CStr, CString, c_char};
+use std::fmt::Write as _;
+use std::sync::LazyLock;
+
+use super::platform::{blue, red};
+
+/// Name of the symbol modules export their build configuration record under,
+/// see `awaken.rs.template` in dylo-cli.
+pub(crate) const BUILD_CONFIG_SYMBOL: &str = "github.com_bearcove_dylo_build_config";
+
+// bump this if the record format changes in an incompatible way
+const RECORD_VERSION: &str = "dylo-build-config/1";
+
+/// The parts of a build configuration that must agree between the host and
+/// the modules it loads. Mixing panic strategies or global allocators, or
+/// forgetting `dylo-runtime/import-globals`, "works" until a `Box` leaked by
+/// a module gets freed by the host, or a panic crosses the boundary.
+///
+/// Values are seen from `dylo-runtime`'s point of view, which is compiled
+/// with the same profile as the host (or module) it's a part of.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BuildConfig {
+    pub panic: String,
+    /// declared with `DYLO_ALLOCATOR` (`system` if unset), not detected
+    pub allocator: String,
+    pub rustc: String,
+    pub debug_assertions: bool,
+    pub globals: String,
+}
+
+impl BuildConfig {
+    pub(crate) fn current() -> Self {
+        Self {
+            panic: if cfg!(panic = "abort") {
+                "abort"
+            } else {
+                "unwind"
+            }
+            .to_string(),
+            allocator: env!("DYLO_ALLOCATOR").to_string(),
+            rustc: env!("DYLO_RUSTC_VERSION").to_string(),
+            debug_assertions: cfg!(debug_assertions),
+            globals: if cfg!(feature = "import-globals") {
+                "import"
+            } else if cfg!(feature = "export-globals") {
+                "export"
+            } else {
+                "none"
+            }
+            .to_string(),
+        }
+    }
+
+    /// Serializes to a `key=value;...` string: it crosses the dylib boundary,
+    /// so it can't be a Rust struct whose layout depends on the compiler.
+    fn to_record(&self) -> String {
+        let mut record = RECORD_VERSION.to_string();
+        let _ = write!(record, ";panic={}", self.panic);
+        let _ = write!(record, ";allocator={}", self.allocator);
+        let _ = write!(record, ";rustc={}", self.rustc);
+        let _ = write!(record, ";debug-assertions={}", self.debug_assertions);
+        let _ = write!(record, ";globals={}", self.globals);
+        record
+    }
+
+    pub(crate) fn from_record(record: &str) -> Result<Self, String> {
+        let mut fields = record.split(';');
+        match fields.next() {
+            Some(RECORD_VERSION) => {}
+            other => {
+                return Err(format!(
+                    "unsupported build configuration record version {other:?} (expected {RECORD_VERSION:?})"
+                ));
+            }
+        }
+
+        let mut config = BuildConfig {
+            panic: String::new(),
+            allocator: String::new(),
+            rustc: String::new(),
+            debug_assertions: false,
+            globals: String::new(),
+        };
+        for field in fields {
+            let Some((key, value)) = field.split_once('=') else {
+                return Err(format!("malformed field {field:?}"));
+            };
+            match key {
+                "panic" => config.panic = value.to_string(),
+                "allocator" => config.allocator = value.to_string(),
+                "rustc" => config.rustc = value.to_string(),
+                "debug-assertions" => config.debug_assertions = value == "true",
+                "globals" => config.globals = value.to_string(),
+                // newer versions may add fields, that's fine
+                _ => {}
+            }
+        }
+        Ok(config)
+    }
+
+    /// Returns a human-readable explanation of every way `module` is
+    /// incompatible with `self` (the host), if any.
+    fn mismatches(&self, module: &BuildConfig) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.panic != module.panic {
+            problems.push(format!(
+                "panic strategy: host uses {}, module uses {}. Set the same `panic` in the [profile] of both builds.",
+                blue(&self.panic),
+                blue(&module.panic)
+            ));
+        }
+        if self.allocator != module.allocator {
+            problems.push(format!(
+                "global allocator, as declared with `DYLO_ALLOCATOR` at build time (the actual `#[global_allocator]` can't be detected): host declares {}, module declares {}. Memory allocated on one side would be freed by the other: use the same `#[global_allocator]`, and set `DYLO_ALLOCATOR` identically for both builds.",
+                blue(&self.allocator),
+                blue(&module.allocator)
+            ));
+        }
+        if self.rustc != module.rustc {
+            problems.push(format!(
+                "compiler: host was built with {}, module with {}. The Rust ABI is only stable within a single compiler version.",
+                blue(&self.rustc),
+                blue(&module.rustc)
+            ));
+        }
+        if self.debug_assertions != module.debug_assertions {
+            problems.push(format!(
+                "debug assertions: {} in host, {} in module. Some crates change their layout depending on them: build both with the same profile.",
+                blue(self.debug_assertions),
+                blue(module.debug_assertions)
+            ));
+        }
+        if module.globals != "import" {
+            problems.push(format!(
+                "rubicon globals: module was built with {}, but modules must enable the {} feature so they share process-local and thread-local state with the host.",
+                blue(format!("{}-globals", module.globals)),
+                blue("dylo-runtime/import-globals")
+            ));
+        }
+
+        problems
+    }
+}
+
+static RECORD: LazyLock<CString> = LazyLock::new(|| {
+    CString::new(BuildConfig::current().to_record()).expect("record has no NUL bytes")
+});
+
+/// Returns this build's configuration record, as a NUL-terminated string.
+///
+/// Modules export this under [`BUILD_CONFIG_SYMBOL`], see `awaken.rs.template`.
+pub fn build_config_record() -> *const c_char {
+    RECORD.as_ptr()
+}
+
+/// Compares the record exported by a module with the host's build configuration,
+/// panicking with an explanation if they're not compatible.
+pub(crate) fn check_module_record(mod_name: &str, record: &CStr) {
+    let record = record.to_string_lossy();
+    let module = BuildConfig::from_record(&record).unwrap_or_else(|e| {
+        panic!(
+            "Could not read the build configuration of module {}: {e}",
+            blue(mod_name)
+        )
+    });
+
+    let problems = BuildConfig::current().mismatches(&module);
+    if problems.is_empty() {
+        return;
+    }
+
+    let mut message = format!(
+        "{} was built with a configuration that is incompatible with this executable:\n",
+        blue(mod_name)
+    );
+    for problem in problems {
+        let _ = writeln!(message, "  {} {problem}", red("✗"));
+    }
+    panic!("{message}");
+}
@@ -0,0 +1,368 @@
+// without the `config` feature, `dylo.toml` is never read: everything here is defaults
+#![cfg_attr(not(feature = "config"), allow(dead_code))]
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+#[cfg(feature = "config")]
+use serde::Deserialize;
+
+#[cfg(feature = "config")]
+use super::current_exe_dir;
+use super::platform::{RTLD_GLOBAL, RTLD_LAZY, RTLD_LOCAL, RTLD_NODELETE, RTLD_NOW, blue};
+
+pub(crate) const CONFIG_FILE_NAME: &str = "dylo.toml";
+
+/// Runtime configuration, read from an optional `dylo.toml` file that lives
+/// next to the executable (or in `../libexec/` relative to it).
+///
+/// Environment variables (`DYLO_DEBUG`, `DYLO_MOD_DIR`) take precedence over
+/// anything set here. Without the `config` feature, the file is ignored and this is
+/// always the default.
+#[derive(Debug, Default)]
+#[cfg_attr(
+    feature = "config",
+    derive(Deserialize),
+    serde(rename_all = "kebab-case", deny_unknown_fields, default)
+)]
+pub(crate) struct Config {
+    /// same as `DYLO_DEBUG=1`
+    pub debug: bool,
+
+    /// only search explicitly configured paths, require absolute paths, and
+    /// refuse to load modules from directories/files writable by group or others
+    pub strict: bool,
+
+    /// extra search paths, searched after `$DYLO_MOD_DIR` and before the
+    /// executable-relative defaults. relative paths are resolved against
+    /// the directory containing `dylo.toml`.
+    pub search_paths: Vec<PathBuf>,
+
+    /// flags passed to `dlopen` for every module, e.g. `["now", "local"]`
+    pub dlopen_flags: Option<Vec<DlopenFlag>>,
+
+    /// shared libraries to `dlopen` (with `RTLD_NOW | RTLD_GLOBAL`) before
+    /// the first module is loaded
+    pub preload: Vec<PathBuf>,
+
+    /// per-module overrides, keyed by module name (without the `mod-` prefix)
+    pub mods: HashMap<String, ModConfig>,
+
+    /// directory the config file was loaded from, if any
+    #[cfg_attr(feature = "config", serde(skip))]
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+#[cfg_attr(
+    feature = "config",
+    derive(Deserialize),
+    serde(rename_all = "kebab-case", deny_unknown_fields, default)
+)]
+pub(crate) struct ModConfig {
+    /// exact path of the module's shared library, bypassing the search paths (but
+    /// not `$DYLO_MOD_DIR`, which is searched first)
+    pub path: Option<PathBuf>,
+
+    /// overrides the top-level `dlopen-flags` for this module
+    pub dlopen_flags: Option<Vec<DlopenFlag>>,
+
+    /// whether to load the module into this process, or to run it in a child
+    /// process (requires the `isolation` feature, and a mod generated with
+    /// isolation enabled)
+    pub isolation: Isolation,
+
+    /// for isolated modules: start a new child process when the previous one crashed
+    #[cfg_attr(not(feature = "isolation"), allow(dead_code))]
+    pub restart: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config",
+    derive(Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub(crate) enum Isolation {
+    #[default]
+    InProcess,
+    Process,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config",
+    derive(Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub(crate) enum DlopenFlag {
+    Lazy,
+    Now,
+    Global,
+    Local,
+    NoDelete,
+}
+
+impl DlopenFlag {
+    fn bits(self) -> i32 {
+        match self {
+            DlopenFlag::Lazy => RTLD_LAZY,
+            DlopenFlag::Now => RTLD_NOW,
+            DlopenFlag::Global => RTLD_GLOBAL,
+            DlopenFlag::Local => RTLD_LOCAL,
+            DlopenFlag::NoDelete => RTLD_NODELETE,
+        }
+    }
+}
+
+static CONFIG: LazyLock<Config> = LazyLock::new(Config::load);
+
+impl Config {
+    pub(crate) fn get() -> &'static Config {
+        &CONFIG
+    }
+
+    #[cfg(not(feature = "config"))]
+    fn load() -> Config {
+        Config::default()
+    }
+
+    // note: this runs before we know whether debug logging is enabled (the
+    // config file can enable it), so it only logs if `DYLO_DEBUG=1` is set.
+    #[cfg(feature = "config")]
+    fn load() -> Config {
+        let verbose = matches!(std::env::var("DYLO_DEBUG").as_deref(), Ok("1"));
+
+        let Some(exe_dir) = current_exe_dir() else {
+            return Config::default();
+        };
+        let candidates = [exe_dir.clone(), exe_dir.join("../libexec")];
+
+        for dir in candidates {
+            let path = dir.join(CONFIG_FILE_NAME);
+            if !path.exists() {
+                continue;
+            }
+            if verbose {
+                eprintln!("Loading dylo config from {}", blue(path.display()));
+            }
+
+            let contents = std::fs::read_to_string(&path).unwrap_or_else(|e| {
+                panic!(
+                    "Failed to read dylo config at {}: {e}",
+                    blue(path.display())
+                )
+            });
+            let mut config: Config = toml::from_str(&contents)
+                .unwrap_or_else(|e| panic!("Invalid dylo config at {}: {e}", blue(path.display())));
+            config.dir = Some(dir.canonicalize().unwrap_or(dir));
+            if let Err(e) = config.validate() {
+                panic!(
+                    "dylo config at {} {e}, refusing to proceed.",
+                    blue(path.display())
+                );
+            }
+            return config;
+        }
+
+        Config::default()
+    }
+
+    /// Checks what the file format can't express. The error completes
+    /// "dylo config at {path} ...".
+    fn validate(&self) -> Result<(), String> {
+        // dlopen requires exactly one of RTLD_LAZY or RTLD_NOW
+        let flag_lists = self
+            .dlopen_flags
+            .iter()
+            .chain(self.mods.values().filter_map(|m| m.dlopen_flags.as_ref()));
+        for flags in flag_lists {
+            if flags.contains(&DlopenFlag::Lazy) && flags.contains(&DlopenFlag::Now) {
+                return Err(
+                    "has both \"lazy\" and \"now\" in dlopen-flags, which are mutually exclusive"
+                        .to_string(),
+                );
+            }
+        }
+
+        if !self.strict {
+            return Ok(());
+        }
+
+        let relative = self
+            .search_paths
+            .iter()
+            .chain(self.preload.iter())
+            .chain(self.mods.values().filter_map(|m| m.path.as_ref()))
+            .find(|p| !p.is_absolute());
+        if let Some(path) = relative {
+            return Err(format!(
+                "is in strict mode, but contains a relative path ({})",
+                blue(path.display())
+            ));
+        }
+        Ok(())
+    }
+
+    /// Resolves a path from the config file against the config's directory.
+    pub(crate) fn resolve(&self, path: &Path) -> PathBuf {
+        match &self.dir {
+            Some(dir) if path.is_relative() => dir.join(path),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    /// Whether `mod_name` should run in a child process rather than in this one.
+    pub(crate) fn is_isolated(&self, mod_name: &str) -> bool {
+        self.mods
+            .get(mod_name)
+            .is_some_and(|m| m.isolation == Isolation::Process)
+    }
+
+    pub(crate) fn dlopen_flags(&self, mod_name: &str) -> i32 {
+        let flags = self
+            .mods
+            .get(mod_name)
+            .and_then(|m| m.dlopen_flags.as_ref())
+            .or(self.dlopen_flags.as_ref());
+
+        let bits = flags
+            .map(|flags| flags.iter().fold(0, |acc, f| acc | f.bits()))
+            .unwrap_or(0);
+
+        // dlopen requires exactly one of RTLD_LAZY or RTLD_NOW
+        if bits & (RTLD_LAZY | RTLD_NOW) == 0 {
+            bits | RTLD_LAZY
+        } else {
+            bits
+        }
+    }
+}
+
+#[cfg(all(test, feature = "config"))]
+mod tests {
+    use super::*;
+
+    fn parse(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn parses_every_key() {
+        let config = parse(
+            r#"
+            debug = true
+            strict = true
+            search-paths = ["/opt/app/mods"]
+            dlopen-flags = ["now", "local"]
+            preload = ["/opt/app/lib/libjemalloc.so"]
+
+            [mods.markdown]
+            path = "/opt/app/mods/libmod_markdown.so"
+            dlopen-flags = ["lazy", "global"]
+            isolation = "process"
+            restart = true
+            "#,
+        );
+        assert!(config.debug && config.strict);
+        assert_eq!(config.search_paths, [PathBuf::from("/opt/app/mods")]);
+        assert_eq!(
+            config.dlopen_flags,
+            Some(vec![DlopenFlag::Now, DlopenFlag::Local])
+        );
+        assert_eq!(config.preload.len(), 1);
+        let markdown = &config.mods["markdown"];
+        assert_eq!(
+            markdown.path.as_deref(),
+            Some(Path::new("/opt/app/mods/libmod_markdown.so"))
+        );
+        assert_eq!(markdown.isolation, Isolation::Process);
+        assert!(markdown.restart);
+        assert!(config.is_isolated("markdown"));
+        assert!(!config.is_isolated("other"));
+
+        // every key is optional
+        let config = parse("");
+        assert!(!config.debug && config.mods.is_empty() && config.dlopen_flags.is_none());
+        assert_eq!(
+            parse("[mods.markdown]").mods["markdown"].isolation,
+            Isolation::InProcess
+        );
+
+        for invalid in [
+            "unknown = 1",
+            "dlopen-flags = [\"eager\"]",
+            "[mods.markdown]\nisolation = \"thread\"",
+        ] {
+            assert!(toml::from_str::<Config>(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn validates() {
+        assert!(
+            parse("dlopen-flags = [\"now\", \"global\"]")
+                .validate()
+                .is_ok()
+        );
+        assert!(
+            parse("dlopen-flags = [\"lazy\", \"now\"]")
+                .validate()
+                .is_err()
+        );
+        assert!(
+            parse("[mods.markdown]\ndlopen-flags = [\"now\", \"lazy\"]")
+                .validate()
+                .is_err()
+        );
+
+        // relative paths are only a problem in strict mode
+        let relative = "search-paths = [\"mods\"]";
+        assert!(parse(relative).validate().is_ok());
+        assert!(
+            parse(&format!("strict = true\n{relative}"))
+                .validate()
+                .is_err()
+        );
+        assert!(
+            parse("strict = true\n[mods.markdown]\npath = \"libmod_markdown.so\"")
+                .validate()
+                .is_err()
+        );
+        assert!(
+            parse("strict = true\nsearch-paths = [\"/opt/app/mods\"]")
+                .validate()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn resolves_against_the_config_dir() {
+        let mut config = parse("");
+        assert_eq!(config.resolve(Path::new("mods")), Path::new("mods"));
+
+        config.dir = Some(PathBuf::from("/opt/app/bin"));
+        assert_eq!(
+            config.resolve(Path::new("mods")),
+            Path::new("/opt/app/bin/mods")
+        );
+        assert_eq!(config.resolve(Path::new("/mods")), Path::new("/mods"));
+    }
+
+    #[test]
+    fn computes_dlopen_flags() {
+        // lazy unless told otherwise
+        assert_eq!(parse("").dlopen_flags("markdown"), RTLD_LAZY);
+        assert_eq!(
+            parse("dlopen-flags = [\"global\"]").dlopen_flags("markdown"),
+            RTLD_LAZY | RTLD_GLOBAL
+        );
+
+        let config = parse(
+            "dlopen-flags = [\"now\", \"local\"]\n[mods.markdown]\ndlopen-flags = [\"lazy\", \"no-delete\"]",
+        );
+        assert_eq!(config.dlopen_flags("other"), RTLD_NOW | RTLD_LOCAL);
+        assert_eq!(config.dlopen_flags("markdown"), RTLD_LAZY | RTLD_NODELETE);
+    }
+}
@@ -0,0 +1,428 @@
+//! Out-of-process modules: instead of `dlopen`ing a module into the host, the
+//! host re-executes itself as a child process that loads the module and serves
+//! calls over a Unix socket. A crash in the module then kills the child rather
+//! than the host, and comes back as an [`IsolationError`].
+//!
+//! The consumer side is a proxy generated by dylo-cli, which implements the
+//! `Mod` trait by serializing arguments and calling [`Client::call`]. The module
+//! side is a dispatcher, also generated by dylo-cli, exported under
+//! [`DISPATCH_SYMBOL`].
+
+use std::ffi::CString;
+use std::io::{Read, Write};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::value::RawValue;
+
+use super::config::Config;
+use super::platform::{blue, dlsym};
+use super::{AnyModRef, Interface, Loaded, check_loaded, load_or_init, open_module, parse_version};
+
+/// Name of the symbol isolated modules export their dispatcher under.
+pub(crate) const DISPATCH_SYMBOL: &str = "github.com_bearcove_dylo_dispatch";
+
+/// Set in the environment of child processes, to the name of the module they should serve.
+const CHILD_MOD_ENV: &str = "DYLO_ISOLATED_MOD";
+
+/// Set in the environment of child processes, to the version requested by the consumer.
+const CHILD_VERSION_ENV: &str = "DYLO_ISOLATED_MOD_VERSION";
+
+/// How long we give a child process to load its module and say hello.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Signature of the dispatcher generated by dylo-cli: takes a method name and
+/// JSON-encoded arguments, returns JSON-encoded results.
+type DispatchFn = fn(method: &str, args: &str) -> Result<String, String>;
+
+/// Implemented by dylo-cli (in the consumer's generated support code) for the
+/// `DynMod` type of mods that have isolation enabled.
+pub trait Isolate: Interface {
+    /// Wraps a client in a proxy that implements `Mod` by forwarding every call.
+    fn proxy(client: Client) -> &'static Self;
+}
+
+/// Something went wrong talking to an isolated module: it crashed, panicked,
+/// or a value could not be (de)serialized.
+///
+/// For methods returning `Result<T, E>`, generated proxies turn this into an
+/// `E` (which must implement `From<IsolationError>`). For other methods, they panic.
+#[derive(Debug, Clone)]
+pub struct IsolationError {
+    message: String,
+}
+
+impl IsolationError {
+    fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for IsolationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for IsolationError {}
+
+#[derive(Serialize)]
+struct Request<'a, A: ?Sized> {
+    method: &'a str,
+    args: &'a A,
+}
+
+#[derive(Deserialize)]
+struct ServerRequest {
+    method: String,
+    args: Box<RawValue>,
+}
+
+enum State {
+    Running { child: Child, stream: UnixStream },
+    Crashed(IsolationError),
+}
+
+/// A connection to a module running in a child process.
+pub struct Client {
+    mod_name: String,
+    version: Option<String>,
+    restart: bool,
+    state: Mutex<State>,
+}
+
+impl Client {
+    fn spawn(mod_name: &str, version: Option<&str>, restart: bool) -> Result<Self, IsolationError> {
+        let (child, stream) = spawn_child(mod_name, version)?;
+        Ok(Self {
+            mod_name: mod_name.to_string(),
+            version: version.map(|v| v.to_string()),
+            restart,
+            state: Mutex::new(State::Running { child, stream }),
+        })
+    }
+
+    /// Calls `method` in the child process. `args` is typically a tuple of
+    /// the method's arguments.
+    pub fn call<A: Serialize + ?Sized, R: DeserializeOwned>(
+        &self,
+        method: &str,
+        args: &A,
+    ) -> Result<R, IsolationError> {
+        let mut state = self.state.lock().unwrap();
+
+        if let State::Crashed(e) = &*state {
+            if !self.restart {
+                return Err(IsolationError::new(format!(
+                    "module {} is not running (restart is disabled in dylo.toml): {e}",
+                    self.mod_name
+                )));
+            }
+            debug!("Restarting isolated module {}", blue(&self.mod_name));
+            let (child, stream) = spawn_child(&self.mod_name, self.version.as_deref())?;
+            *state = State::Running { child, stream };
+        }
+
+        let State::Running { child, stream } = &mut *state else {
+            unreachable!()
+        };
+
+        let request = serde_json::to_vec(&Request { method, args })
+            .map_err(|e| IsolationError::new(format!("could not serialize arguments: {e}")))?;
+        let response = write_frame(stream, &request).and_then(|()| read_frame(stream));
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                // the child is gone (or hopelessly confused): reap it and
+                // report how it went away.
+                let _ = child.kill();
+                let status = child
+                    .wait()
+                    .map(|s| s.to_string())
+                    .unwrap_or_else(|e| e.to_string());
+                let e = IsolationError::new(format!(
+                    "module {} crashed while handling {method} ({status}): {e}",
+                    self.mod_name
+                ));
+                *state = State::Crashed(e.clone());
+                return Err(e);
+            }
+        };
+
+        let response: Result<R, String> = serde_json::from_slice(&response)
+            .map_err(|e| IsolationError::new(format!("could not deserialize result: {e}")))?;
+        response.map_err(IsolationError::new)
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let Ok(State::Running { child, .. }) = self.state.get_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Re-executes the current executable with the module to serve in its
+/// environment, and one end of a socket pair as its stdin.
+fn spawn_child(
+    mod_name: &str,
+    version: Option<&str>,
+) -> Result<(Child, UnixStream), IsolationError> {
+    let exe = std::env::current_exe()
+        .map_err(|e| IsolationError::new(format!("could not find current executable: {e}")))?;
+    let (mut stream, child_stream) = UnixStream::pair()
+        .map_err(|e| IsolationError::new(format!("could not create socket pair: {e}")))?;
+
+    let mut command = Command::new(exe);
+    command
+        .env(CHILD_MOD_ENV, mod_name)
+        .stdin(Stdio::from(OwnedFd::from(child_stream)));
+    if let Some(version) = version {
+        command.env(CHILD_VERSION_ENV, version);
+    }
+    let mut child = command
+        .spawn()
+        .map_err(|e| IsolationError::new(format!("could not spawn module process: {e}")))?;
+
+    // wait for the child to say hello, which it does once the module is loaded
+    let hello = stream
+        .set_read_timeout(Some(STARTUP_TIMEOUT))
+        .and_then(|()| read_frame(&mut stream))
+        .and_then(|hello| stream.set_read_timeout(None).map(|()| hello));
+    if let Err(e) = hello {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(IsolationError::new(format!(
+            "module process for {mod_name} did not start ({e}). Does the executable call `dylo_runtime::details::isolation::serve_if_child()` at the start of `main`?"
+        )));
+    }
+
+    debug!(
+        "Spawned isolated module {} (pid {})",
+        blue(mod_name),
+        child.id()
+    );
+    Ok((child, stream))
+}
+
+fn write_frame(stream: &mut UnixStream, payload: &[u8]) -> std::io::Result<()> {
+    let len = u32::try_from(payload.len()).map_err(std::io::Error::other)?;
+    stream.write_all(&len.to_le_bytes())?;
+    stream.write_all(payload)?;
+    stream.flush()
+}
+
+fn read_frame(stream: &mut UnixStream) -> std::io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let mut payload = vec![0u8; u32::from_le_bytes(len) as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// Like [`super::load_typed`], but runs the module in a child process if
+/// `dylo.toml` says so.
+pub fn load_isolatable<T: ?Sized + Isolate>(mod_name: &str, version: Option<&str>) -> &'static T {
+    if !Config::get().is_isolated(mod_name) {
+        return super::load_typed::<T>(mod_name, version);
+    }
+
+    if std::env::var_os(CHILD_MOD_ENV).is_some() {
+        panic!(
+            "{} is configured to run isolated, but this process is itself an isolated module process: call `dylo_runtime::details::isolation::serve_if_child()` at the start of `main`",
+            blue(mod_name)
+        );
+    }
+
+    // fail early, in the host, rather than in the child
+    let parsed_version = version.map(|version| parse_version(mod_name, version));
+
+    let restart = Config::get().mods.get(mod_name).is_some_and(|m| m.restart);
+    let loaded = load_or_init(mod_name, || {
+        let client = Client::spawn(mod_name, version, restart).unwrap_or_else(|e| panic!("{e}"));
+        let proxy: &'static T = T::proxy(client);
+        Loaded {
+            // SAFETY: see `load_typed`, `Interface` guarantees `&T` is a `dyn Trait` fat pointer.
+            module: unsafe { std::mem::transmute_copy::<&'static T, AnyModRef>(&proxy) },
+            interface_id: Some(T::ID),
+            version: None,
+        }
+    });
+    let fat_pointer = check_loaded(mod_name, &loaded, parsed_version.as_ref(), Some(T::ID));
+    // SAFETY: `check_loaded` made sure whatever is cached implements `T`.
+    unsafe { std::mem::transmute_copy::<AnyModRef, &'static T>(&fat_pointer) }
+}
+
+/// If this process was spawned to serve an isolated module, serves it until the
+/// host goes away, then exits. Otherwise, returns immediately.
+///
+/// Executables that load isolated modules must call this at the very start of `main`,
+/// since isolated modules run in a copy of the executable.
+pub fn serve_if_child() {
+    let Some(mod_name) = std::env::var_os(CHILD_MOD_ENV) else {
+        return;
+    };
+    let mod_name = mod_name.to_string_lossy().into_owned();
+    let version = std::env::var(CHILD_VERSION_ENV)
+        .ok()
+        .map(|v| parse_version(&mod_name, &v));
+
+    // SAFETY: the host gave us one end of a socket pair as stdin, and nothing
+    // else in this process is going to use stdin.
+    let mut stream = UnixStream::from(unsafe { OwnedFd::from_raw_fd(0) });
+
+    let (handle, _) = open_module(&mod_name, version.as_ref());
+    let symbol_name = CString::new(DISPATCH_SYMBOL).unwrap();
+    let dispatch_sym = unsafe { dlsym(handle, symbol_name.as_ptr() as *const _) };
+    if dispatch_sym.is_null() {
+        eprintln!(
+            "Module {} has no dispatcher: enable isolation in its Cargo.toml (`[package.metadata.dylo] isolation = true`) and run `dylo gen`",
+            blue(&mod_name)
+        );
+        std::process::exit(1);
+    }
+    let dispatch: DispatchFn = unsafe { std::mem::transmute(dispatch_sym) };
+
+    if write_frame(&mut stream, b"hello").is_err() {
+        std::process::exit(1);
+    }
+    serve(&mut stream, &mod_name, dispatch);
+    std::process::exit(0);
+}
+
+/// Answers requests from the host until it goes away (or closes the connection).
+fn serve(stream: &mut UnixStream, mod_name: &str, dispatch: DispatchFn) {
+    while let Ok(request) = read_frame(stream) {
+        let response = match serde_json::from_slice::<ServerRequest>(&request) {
+            Ok(request) => {
+                std::panic::catch_unwind(|| dispatch(&request.method, request.args.get()))
+                    .unwrap_or_else(|panic| {
+                        let message = panic
+                            .downcast_ref::<&str>()
+                            .map(|s| s.to_string())
+                            .or_else(|| panic.downcast_ref::<String>().cloned())
+                            .unwrap_or_else(|| "unknown panic payload".to_string());
+                        Err(format!("module {mod_name} panicked: {message}"))
+                    })
+            }
+            Err(e) => Err(format!("malformed request: {e}")),
+        };
+
+        let response = match response {
+            Ok(value) => RawValue::from_string(value)
+                .map(Ok)
+                .unwrap_or_else(|e| Err(format!("module returned invalid JSON: {e}"))),
+            Err(e) => Err(e),
+        };
+        let response: Result<Box<RawValue>, String> = response;
+        let payload = serde_json::to_vec(&response).expect("results are always serializable");
+        if write_frame(stream, &payload).is_err() {
+            break;
+        }
+    }
+}
+
+/// Used by generated dispatchers to decode a method's arguments.
+pub fn decode_args<T: DeserializeOwned>(method: &str, args: &str) -> Result<T, String> {
+    serde_json::from_str(args)
+        .map_err(|e| format!("could not deserialize arguments of {method}: {e}"))
+}
+
+/// Used by generated dispatchers to encode a method's result.
+pub fn encode_result<T: Serialize>(method: &str, result: &T) -> Result<String, String> {
+    serde_json::to_string(result)
+        .map_err(|e| format!("could not serialize result of {method}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A dispatcher like the ones dylo-cli generates, for a mod with
+    /// `fn add(&self, a: u32, b: u32) -> u32` and `fn boom(&self)`.
+    fn dispatch(method: &str, args: &str) -> Result<String, String> {
+        match method {
+            "add" => {
+                let (a, b): (u32, u32) = decode_args(method, args)?;
+                encode_result(method, &(a + b))
+            }
+            "boom" => panic!("kaboom"),
+            _ => Err(format!("unknown method {method}")),
+        }
+    }
+
+    /// A client talking to `stream`, with a stand-in for the module process.
+    fn client(stream: UnixStream, restart: bool) -> Client {
+        let child = Command::new("sleep").arg("60").spawn().unwrap();
+        Client {
+            mod_name: "test".to_string(),
+            version: None,
+            restart,
+            state: Mutex::new(State::Running { child, stream }),
+        }
+    }
+
+    #[test]
+    fn frames_roundtrip() {
+        let (mut a, mut b) = UnixStream::pair().unwrap();
+        write_frame(&mut a, b"").unwrap();
+        write_frame(&mut a, b"hello").unwrap();
+        assert_eq!(read_frame(&mut b).unwrap(), b"");
+        assert_eq!(read_frame(&mut b).unwrap(), b"hello");
+
+        drop(a);
+        assert!(read_frame(&mut b).is_err());
+    }
+
+    #[test]
+    fn calls_roundtrip() {
+        let (host, mut module) = UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || serve(&mut module, "test", dispatch));
+
+        let client = client(host, false);
+        assert_eq!(client.call::<_, u32>("add", &(2, 3)).unwrap(), 5);
+
+        let e = client.call::<_, ()>("boom", &()).unwrap_err();
+        assert!(
+            e.to_string().contains("module test panicked: kaboom"),
+            "{e}"
+        );
+        let e = client.call::<_, u32>("add", &("two", 3)).unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("could not deserialize arguments of add"),
+            "{e}"
+        );
+
+        // panics and bad arguments don't take the module down
+        assert_eq!(client.call::<_, u32>("add", &(40, 2)).unwrap(), 42);
+
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn crashed_modules_stay_down_without_restart() {
+        let (host, module) = UnixStream::pair().unwrap();
+        let client = client(host, false);
+        // what the host sees of a module process that died
+        drop(module);
+
+        let e = client.call::<_, u32>("add", &(2, 3)).unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("module test crashed while handling add"),
+            "{e}"
+        );
+        let e = client.call::<_, u32>("add", &(2, 3)).unwrap_err();
+        assert!(e.to_string().contains("restart is disabled"), "{e}");
+    }
+}
@@ -76,12 +76,26 @@ pub(crate) fn blue(t: impl Display) -> impl Display {
     colorize(34, t)
 }
 
-#[allow(dead_code)]
 pub(crate) fn red(t: impl Display) -> impl Display {
     colorize(31, t)
 }
 
 pub const RTLD_LAZY: i32 = 0x1;
+pub const RTLD_NOW: i32 = 0x2;
+
+#[cfg(target_os = "macos")]
+pub const RTLD_LOCAL: i32 = 0x4;
+#[cfg(target_os = "macos")]
+pub const RTLD_GLOBAL: i32 = 0x8;
+#[cfg(target_os = "macos")]
+pub const RTLD_NODELETE: i32 = 0x80;
+
+#[cfg(not(target_os = "macos"))]
+pub const RTLD_LOCAL: i32 = 0x0;
+#[cfg(not(target_os = "macos"))]
+pub const RTLD_GLOBAL: i32 = 0x100;
+#[cfg(not(target_os = "macos"))]
+pub const RTLD_NODELETE: i32 = 0x1000;
 
 unsafe extern "C" {
     pub fn dlopen(filename: *const i8, flags: i32) -> *mut std::ffi::c_void;
@@ -0,0 +1,93 @@
+use std::ffi::CStr;
+use std::sync::OnceLock;
+
+use super::config::Config;
+use super::platform::blue;
+use super::{AnyModRef, load};
+
+/// Name of the symbol modules export the [`Interface::ID`] of their `Mod`
+/// trait under, see `awaken.rs.template` in dylo-cli.
+pub(crate) const INTERFACE_SYMBOL: &str = "github.com_bearcove_dylo_interface";
+
+/// Implemented by dylo-cli (in the generated `spec.rs`) for the `DynMod`
+/// type of every mod: `dyn Mod`, with its associated types spelled out.
+///
+/// # Safety
+///
+/// `Self` must be a `dyn Trait` type, so that `&'static Self` is a fat
+/// pointer with the same layout as [`AnyModRef`]. `ID` must change whenever
+/// the trait definition changes: it's what lets the loader notice that a
+/// consumer and a module were generated from different specs.
+pub unsafe trait Interface: 'static {
+    const ID: &'static CStr;
+}
+
+/// Loads a module and returns it as a `&'static dyn Mod`, checking that the
+/// module was built against the same `Mod` trait as the caller.
+///
+/// Unlike [`super::load_mod`], the module name doesn't need to be `'static`.
+pub fn load_typed<T: ?Sized + Interface>(mod_name: &str, version: Option<&str>) -> &'static T {
+    const {
+        assert!(
+            size_of::<&'static T>() == size_of::<AnyModRef>(),
+            "Interface must only be implemented for `dyn Trait` types"
+        )
+    };
+
+    if Config::get().is_isolated(mod_name) {
+        panic!(
+            "dylo.toml asks for {} to run isolated, but its consumer was generated without isolation support: set `[package.metadata.dylo] isolation = true` in the mod's Cargo.toml and run `dylo gen`",
+            blue(mod_name)
+        );
+    }
+
+    let version = version.map(|version| super::parse_version(mod_name, version));
+    let fat_pointer = load(mod_name, version.as_ref(), Some(T::ID));
+
+    // SAFETY: both are fat pointers to `dyn Trait` (guaranteed by `Interface`'s
+    // contract and the size assertion above), and `load` checked that the
+    // module's `Mod` trait has the same identity as `T`, whether it was just
+    // loaded or already cached (modules that predate interface identities
+    // can't be checked).
+    unsafe { std::mem::transmute_copy::<AnyModRef, &'static T>(&fat_pointer) }
+}
+
+/// A lazily-loaded module, suitable for a `static`.
+///
+/// ```rust,ignore
+/// pub fn load() -> &'static DynMod {
+///     static MOD: ModSlot<DynMod> = ModSlot::new(env!("CARGO_PKG_NAME"), Some(env!("CARGO_PKG_VERSION")));
+///     MOD.get()
+/// }
+/// ```
+pub struct ModSlot<T: ?Sized + Interface> {
+    mod_name: &'static str,
+    version: Option<&'static str>,
+    module: OnceLock<&'static T>,
+}
+
+impl<T: ?Sized + Interface> ModSlot<T> {
+    pub const fn new(mod_name: &'static str, version: Option<&'static str>) -> Self {
+        Self {
+            mod_name,
+            version,
+            module: OnceLock::new(),
+        }
+    }
+
+    /// Loads the module on first call, then returns the same reference.
+    pub fn get(&self) -> &'static T {
+        self.module
+            .get_or_init(|| load_typed::<T>(self.mod_name, self.version))
+    }
+}
+
+#[cfg(feature = "isolation")]
+impl<T: ?Sized + super::isolation::Isolate> ModSlot<T> {
+    /// Like [`ModSlot::get`], but runs the module in a child process if
+    /// `dylo.toml` says so.
+    pub fn get_isolatable(&self) -> &'static T {
+        self.module
+            .get_or_init(|| super::isolation::load_isolatable::<T>(self.mod_name, self.version))
+    }
+}
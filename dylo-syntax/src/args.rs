@@ -0,0 +1,272 @@
+//! Arguments of `#[dylo::export(...)]`:
+//!
+//!   * `nonsync`: the trait doesn't require `Sync` (nor do its futures require `Send`)
+//!   * `unsend`: the trait requires neither `Send` nor `Sync`
+//!   * `supertraits(Debug, ...)`: more bounds for the trait
+//!   * `name = "Other"`: names the generated trait differently from the impl's
+//!   * `vis = "pub(crate)"`: visibility of the generated trait (`pub` by default)
+//!   * `unsafe`: generates an `unsafe trait`, for `unsafe impl`s
+//!   * `instantiate(T = User, ...)`: see [`crate::instantiate`]
+//!   * `forward(path, ...)`: attributes to copy to the trait and its methods
+//!
+//! dylo-cli acts on most of these when it generates the trait, the `#[dylo::export]`
+//! macro only needs them to make the impl match it. Both parse them with this.
+
+use syn::{
+    Attribute, Ident, LitStr, Meta, Path, Token, TypeParamBound, Visibility,
+    parse::{Parse, ParseStream},
+    punctuated::Punctuated,
+};
+
+use crate::instantiate::Instantiation;
+
+/// Everything that can go in `#[dylo::export(...)]`.
+#[derive(Default)]
+pub struct ExportArgs {
+    /// Which auto traits the trait requires
+    pub interface: InterfaceType,
+    /// Bounds for the trait, on top of the auto traits
+    pub supertraits: Vec<TypeParamBound>,
+    /// Name of the generated trait, if not the impl's
+    pub name: Option<Ident>,
+    /// Visibility of the generated trait, if not `pub`
+    pub vis: Option<Visibility>,
+    /// The trait is an `unsafe trait`
+    pub unsafety: bool,
+    /// Types the impl's type parameters are instantiated with
+    pub instantiations: Vec<Instantiation>,
+    /// Attributes to copy to the trait and its methods, on top of the usual ones
+    pub forward: Vec<Path>,
+}
+
+/// Auto traits the generated trait requires, see `nonsync` and `unsend` in [`ExportArgs`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum InterfaceType {
+    Unsend,
+    NonSync,
+    #[default]
+    Sync,
+}
+
+impl InterfaceType {
+    /// Whether the futures of `async fn`s must be `Send`, which they can only be if
+    /// the trait object is `Sync`.
+    pub fn send_futures(self) -> bool {
+        self == InterfaceType::Sync
+    }
+
+    /// Bounds of the generated trait: the auto traits, `'static`, and `extra`.
+    pub fn supertraits(self, extra: &[TypeParamBound]) -> Punctuated<TypeParamBound, Token![+]> {
+        let mut p = Punctuated::new();
+        match self {
+            InterfaceType::Unsend => {
+                p.push(syn::parse_quote!('static));
+            }
+            InterfaceType::NonSync => {
+                p.push(syn::parse_quote!(Send));
+                p.push(syn::parse_quote!('static));
+            }
+            InterfaceType::Sync => {
+                p.push(syn::parse_quote!(Send));
+                p.push(syn::parse_quote!(Sync));
+                p.push(syn::parse_quote!('static));
+            }
+        }
+        p.extend(extra.iter().cloned());
+        p
+    }
+}
+
+impl ExportArgs {
+    /// Parses the arguments of `attr`, a `#[dylo::export]` attribute.
+    pub fn from_attr(attr: &Attribute) -> syn::Result<Self> {
+        match &attr.meta {
+            Meta::Path(_) => Ok(Self::default()),
+            Meta::List(list) => list.parse_args(),
+            Meta::NameValue(_) => Err(syn::Error::new_spanned(
+                attr,
+                "expected `#[dylo::export]` or `#[dylo::export(...)]`",
+            )),
+        }
+    }
+}
+
+impl Parse for ExportArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut args = Self::default();
+        let mut seen: Vec<String> = Vec::new();
+
+        while !input.is_empty() {
+            let ident = if input.peek(Token![unsafe]) {
+                let token: Token![unsafe] = input.parse()?;
+                Ident::new("unsafe", token.span)
+            } else {
+                input.parse::<Ident>()?
+            };
+
+            let key = ident.to_string();
+            let repeatable = key == "instantiate" || key == "forward";
+            if !repeatable && seen.contains(&key) {
+                return Err(syn::Error::new(
+                    ident.span(),
+                    format!("duplicate argument `{key}`"),
+                ));
+            }
+            seen.push(key.clone());
+
+            match key.as_str() {
+                "nonsync" | "unsend" => {
+                    if seen.iter().any(|k| k == "nonsync") && seen.iter().any(|k| k == "unsend") {
+                        return Err(syn::Error::new(
+                            ident.span(),
+                            "`nonsync` and `unsend` are mutually exclusive (`unsend` implies `nonsync`)",
+                        ));
+                    }
+                    args.interface = if key == "nonsync" {
+                        InterfaceType::NonSync
+                    } else {
+                        InterfaceType::Unsend
+                    };
+                }
+                "supertraits" => {
+                    let content;
+                    syn::parenthesized!(content in input);
+                    let bounds =
+                        Punctuated::<TypeParamBound, Token![,]>::parse_terminated(&content)?;
+                    args.supertraits.extend(bounds);
+                }
+                "name" => {
+                    input.parse::<Token![=]>()?;
+                    let lit: LitStr = input.parse()?;
+                    args.name = Some(lit.parse().map_err(|_| {
+                        syn::Error::new(
+                            lit.span(),
+                            "expected a trait name, like `name = \"Client\"`",
+                        )
+                    })?);
+                }
+                "vis" => {
+                    input.parse::<Token![=]>()?;
+                    let lit: LitStr = input.parse()?;
+                    args.vis = Some(lit.parse().map_err(|_| {
+                        syn::Error::new(
+                            lit.span(),
+                            "expected a visibility, like `vis = \"pub(crate)\"`",
+                        )
+                    })?);
+                }
+                "unsafe" => args.unsafety = true,
+                "instantiate" => {
+                    let content;
+                    syn::parenthesized!(content in input);
+                    let instantiations =
+                        Punctuated::<Instantiation, Token![,]>::parse_terminated(&content)?;
+                    args.instantiations.extend(instantiations);
+                }
+                "forward" => {
+                    let content;
+                    syn::parenthesized!(content in input);
+                    let paths = content.parse_terminated(Path::parse_mod_style, Token![,])?;
+                    args.forward.extend(paths);
+                }
+                _ => {
+                    return Err(syn::Error::new(
+                        ident.span(),
+                        "unknown argument, expected one of `nonsync`, `unsend`, `supertraits(...)`, `name = \"...\"`, `vis = \"...\"`, `unsafe`, `instantiate(...)`, `forward(...)`",
+                    ));
+                }
+            }
+
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+        }
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quote::ToTokens as _;
+
+    use super::*;
+
+    fn parse(attr: &str) -> syn::Result<ExportArgs> {
+        let attrs = syn::parse_str::<syn::DeriveInput>(&format!("{attr} struct S;"))
+            .unwrap()
+            .attrs;
+        ExportArgs::from_attr(&attrs[0])
+    }
+
+    fn instantiations(args: &ExportArgs) -> Vec<String> {
+        args.instantiations
+            .iter()
+            .map(|instantiation| {
+                instantiation
+                    .bindings
+                    .iter()
+                    .map(|(param, ty)| format!("{param}={}", ty.to_token_stream()))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_arguments() {
+        let args = parse("#[dylo::export]").unwrap();
+        assert!(matches!(args.interface, InterfaceType::Sync));
+        assert!(args.instantiations.is_empty());
+
+        let args = parse("#[dylo::export(nonsync)]").unwrap();
+        assert!(matches!(args.interface, InterfaceType::NonSync));
+
+        let args = parse("#[dylo::export(unsend, unsafe)]").unwrap();
+        assert!(matches!(args.interface, InterfaceType::Unsend));
+        assert!(args.unsafety);
+
+        let args = parse(
+            r#"#[dylo::export(supertraits(std::fmt::Debug, Clone), name = "Other", vis = "pub(crate)")]"#,
+        )
+        .unwrap();
+        let supertraits = args
+            .supertraits
+            .iter()
+            .map(|b| b.to_token_stream().to_string());
+        assert_eq!(
+            supertraits.collect::<Vec<_>>(),
+            ["std :: fmt :: Debug", "Clone"]
+        );
+        assert_eq!(args.name.unwrap(), "Other");
+        assert_eq!(
+            args.vis.unwrap().to_token_stream().to_string(),
+            "pub (crate)"
+        );
+
+        let args = parse("#[dylo::export(instantiate(T = User, T = Vec<u8>), nonsync)]").unwrap();
+        assert!(matches!(args.interface, InterfaceType::NonSync));
+        assert_eq!(instantiations(&args), ["T=User", "T=Vec < u8 >"]);
+
+        let args = parse("#[dylo::export(instantiate((K = u64, V = User)))]").unwrap();
+        assert_eq!(instantiations(&args), ["K=u64,V=User"]);
+
+        let args = parse("#[dylo::export(forward(serde, tracing::instrument))]").unwrap();
+        let forward = args.forward.iter().map(|p| p.to_token_stream().to_string());
+        assert_eq!(
+            forward.collect::<Vec<_>>(),
+            ["serde", "tracing :: instrument"]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert!(parse("#[dylo::export(sendable)]").is_err());
+        assert!(parse("#[dylo::export(instantiate(User))]").is_err());
+        assert!(parse(r#"#[dylo::export = "nonsync"]"#).is_err());
+        assert!(parse("#[dylo::export(nonsync, nonsync)]").is_err());
+        assert!(parse("#[dylo::export(nonsync, unsend)]").is_err());
+        assert!(parse(r#"#[dylo::export(name = "not a name")]"#).is_err());
+        assert!(parse(r#"#[dylo::export(vis = "crate-only")]"#).is_err());
+        assert!(parse("#[dylo::export(name = Other)]").is_err());
+    }
+}
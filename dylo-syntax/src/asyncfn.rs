@@ -0,0 +1,158 @@
+//! `async fn` in exported impls: they're not dyn-compatible, so dylo-cli declares
+//! them in the generated trait as returning a boxed future instead, and
+//! `#[dylo::export]` rewrites the impl to match. Both use the signature produced
+//! here, so they can't disagree.
+
+use proc_macro2::Span;
+use syn::{
+    FnArg, GenericParam, Generics, Lifetime, LifetimeParam, ReturnType, Signature, Type,
+    visit_mut::{self, VisitMut},
+};
+
+/// The lifetime of the boxed future.
+const ASYNC_LIFETIME: &str = "'dylo_async";
+
+/// Turns `async fn f(&self, s: &str) -> T` into
+/// `fn f<'life0, 'life1, 'dylo_async>(&'life0 self, s: &'life1 str) -> Pin<Box<dyn Future<Output = T> + Send + 'dylo_async>>`
+/// with every input lifetime (including `outer` ones, from the impl) outliving
+/// `'dylo_async`, which is what `async fn` captures. `send` is false for `nonsync` traits.
+pub fn desugar_async_signature(sig: &Signature, outer: &Generics, send: bool) -> Signature {
+    let mut sig = sig.clone();
+    sig.asyncness = None;
+
+    // name elided lifetimes, so the future can be bound by all of them
+    let mut namer = ElidedLifetimeNamer { count: 0 };
+    for input in sig.inputs.iter_mut() {
+        match input {
+            FnArg::Receiver(receiver) => {
+                if let Some((_, lifetime)) = &mut receiver.reference {
+                    if lifetime.as_ref().is_none_or(|l| l.ident == "_") {
+                        *lifetime = Some(namer.next());
+                    }
+                    // the receiver's type mirrors `reference`
+                    let lifetime = lifetime.clone();
+                    if let Type::Reference(r) = &mut *receiver.ty {
+                        r.lifetime = lifetime;
+                    }
+                } else {
+                    namer.visit_type_mut(&mut receiver.ty);
+                }
+            }
+            FnArg::Typed(pat_type) => namer.visit_type_mut(&mut pat_type.ty),
+        }
+    }
+    let named: Vec<Lifetime> = (0..namer.count)
+        .map(|i| Lifetime::new(&format!("'life{i}"), Span::call_site()))
+        .collect();
+
+    let async_lifetime = Lifetime::new(ASYNC_LIFETIME, Span::call_site());
+    let existing: Vec<Lifetime> = outer
+        .lifetimes()
+        .chain(sig.generics.lifetimes())
+        .map(|param| param.lifetime.clone())
+        .collect();
+
+    for lifetime in &named {
+        sig.generics
+            .params
+            .push(GenericParam::Lifetime(LifetimeParam::new(lifetime.clone())));
+    }
+    sig.generics
+        .params
+        .push(GenericParam::Lifetime(LifetimeParam::new(
+            async_lifetime.clone(),
+        )));
+
+    let where_clause = sig.generics.make_where_clause();
+    for lifetime in existing.iter().chain(&named) {
+        where_clause
+            .predicates
+            .push(syn::parse_quote!(#lifetime: #async_lifetime));
+    }
+
+    let output: Type = match &sig.output {
+        ReturnType::Default => syn::parse_quote!(()),
+        ReturnType::Type(_, ty) => (**ty).clone(),
+    };
+    sig.output = if send {
+        syn::parse_quote! {
+            -> ::std::pin::Pin<::std::boxed::Box<dyn ::std::future::Future<Output = #output> + ::std::marker::Send + #async_lifetime>>
+        }
+    } else {
+        syn::parse_quote! {
+            -> ::std::pin::Pin<::std::boxed::Box<dyn ::std::future::Future<Output = #output> + #async_lifetime>>
+        }
+    };
+    sig
+}
+
+struct ElidedLifetimeNamer {
+    count: usize,
+}
+
+impl ElidedLifetimeNamer {
+    fn next(&mut self) -> Lifetime {
+        let lifetime = Lifetime::new(&format!("'life{}", self.count), Span::call_site());
+        self.count += 1;
+        lifetime
+    }
+}
+
+impl VisitMut for ElidedLifetimeNamer {
+    fn visit_type_reference_mut(&mut self, i: &mut syn::TypeReference) {
+        if i.lifetime.is_none() {
+            i.lifetime = Some(self.next());
+        }
+        visit_mut::visit_type_reference_mut(self, i);
+    }
+
+    fn visit_lifetime_mut(&mut self, i: &mut Lifetime) {
+        if i.ident == "_" {
+            *i = self.next();
+        }
+    }
+
+    // lifetimes elided in `fn(&T)` and `Fn(&T)` are higher-ranked, leave them alone
+    fn visit_type_bare_fn_mut(&mut self, _i: &mut syn::TypeBareFn) {}
+
+    fn visit_parenthesized_generic_arguments_mut(
+        &mut self,
+        _i: &mut syn::ParenthesizedGenericArguments,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quote::ToTokens as _;
+
+    use super::*;
+
+    fn desugar(impl_generics: &str, sig: &str, send: bool) -> String {
+        let outer: Generics = syn::parse_str(impl_generics).unwrap();
+        let sig: Signature = syn::parse_str(sig).unwrap();
+        desugar_async_signature(&sig, &outer, send)
+            .to_token_stream()
+            .to_string()
+    }
+
+    #[test]
+    fn names_elided_lifetimes() {
+        assert_eq!(
+            desugar(
+                "<'a>",
+                "async fn get(&self, key: &'a str, other: Foo<'_>) -> u32",
+                true
+            ),
+            "fn get < 'life0 , 'life1 , 'dylo_async > (& 'life0 self , key : & 'a str , other : Foo < 'life1 >) -> :: std :: pin :: Pin < :: std :: boxed :: Box < dyn :: std :: future :: Future < Output = u32 > + :: std :: marker :: Send + 'dylo_async > > where 'a : 'dylo_async , 'life0 : 'dylo_async , 'life1 : 'dylo_async"
+        );
+    }
+
+    #[test]
+    fn leaves_higher_ranked_lifetimes_alone() {
+        assert_eq!(
+            desugar("", "async fn run(&self, f: &dyn Fn(&str) -> &str)", false),
+            "fn run < 'life0 , 'life1 , 'dylo_async > (& 'life0 self , f : & 'life1 dyn Fn (& str) -> & str) -> :: std :: pin :: Pin < :: std :: boxed :: Box < dyn :: std :: future :: Future < Output = () > + 'dylo_async > > where 'life0 : 'dylo_async , 'life1 : 'dylo_async"
+        );
+    }
+}
@@ -0,0 +1,178 @@
+//! Detects an out-of-date `spec.rs` when the mod builds: next to each trait, dylo-cli
+//! records a hash of the exported impl it was generated from, and `#[dylo::export]`
+//! asserts that the impl still hashes to it.
+//!
+//! Only what the generated trait depends on is hashed: the export arguments, the trait
+//! path and generics, method signatures (and bodies of provided methods), associated
+//! types, and the `#[cfg]`/`#[cfg_attr]` attributes of methods and associated types.
+//!
+//! The impl's own `#[cfg]`/`#[cfg_attr]` aren't: rustc evaluates them before
+//! `#[dylo::export]` sees the impl, so the macro couldn't hash them like dylo-cli does.
+//! They can't drift either, since both the generated trait and its hash are put under
+//! the same `#[cfg]`s.
+
+use quote::ToTokens as _;
+use syn::{ImplItem, ItemImpl, ext::IdentExt as _};
+
+use crate::{fnv1a, is_dylo_provided};
+
+/// Name of the const that holds the hash of the impl `trait_ident` was generated from.
+/// The trait's name is kept as written (not uppercased, so `Foo` and `FOO` don't
+/// collide): the const needs `#[allow(non_upper_case_globals)]`.
+pub fn hash_const_ident(trait_ident: &syn::Ident) -> syn::Ident {
+    quote::format_ident!("__DYLO_SPEC_HASH_{}", trait_ident.unraw())
+}
+
+/// Hash of `imp`, as exported with `args` (the attribute's arguments, empty for
+/// a bare `#[dylo::export]`).
+pub fn impl_hash(imp: &ItemImpl, args: &proc_macro2::TokenStream) -> u64 {
+    let args = normalized(args.clone());
+    let mut input = format!("{args} | ");
+    if let Some((_, trait_path, _)) = &imp.trait_ {
+        input.push_str(&normalized(trait_path.to_token_stream()));
+    }
+    input.push_str(&format!(
+        " | {}",
+        normalized(imp.generics.to_token_stream())
+    ));
+    if let Some(where_clause) = &imp.generics.where_clause {
+        input.push_str(&format!(" {}", normalized(where_clause.to_token_stream())));
+    }
+
+    for item in &imp.items {
+        match item {
+            ImplItem::Fn(f) => {
+                input.push_str(&format!(
+                    " | {}{}",
+                    cfgs(&f.attrs),
+                    normalized(f.sig.to_token_stream())
+                ));
+                if f.attrs.iter().any(is_dylo_provided) {
+                    input.push_str(&format!(" {}", normalized(f.block.to_token_stream())));
+                }
+            }
+            ImplItem::Type(t) => {
+                let (ident, generics, ty) = (&t.ident, &t.generics, &t.ty);
+                input.push_str(&format!(
+                    " | {}type {ident} {} = {}",
+                    cfgs(&t.attrs),
+                    normalized(generics.to_token_stream()),
+                    normalized(ty.to_token_stream())
+                ));
+            }
+            _ => {}
+        }
+    }
+    fnv1a(input.as_bytes())
+}
+
+/// The `#[cfg]` and `#[cfg_attr]` attributes of an impl item, which rustc hands to the
+/// macro as written.
+fn cfgs(attrs: &[syn::Attribute]) -> String {
+    attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("cfg") || attr.path().is_ident("cfg_attr"))
+        .map(|attr| normalized(attr.to_token_stream()))
+        .collect()
+}
+
+/// Tokens separated by single spaces: rustc and proc-macro2 don't print token streams
+/// the same way, and the hash is computed with one on each side.
+fn normalized(tokens: proc_macro2::TokenStream) -> String {
+    use proc_macro2::{Delimiter, TokenTree};
+
+    let mut out = String::new();
+    for token in tokens {
+        match token {
+            TokenTree::Group(group) => {
+                let (open, close) = match group.delimiter() {
+                    Delimiter::Parenthesis => ("(", ")"),
+                    Delimiter::Brace => ("{", "}"),
+                    Delimiter::Bracket => ("[", "]"),
+                    Delimiter::None => ("", ""),
+                };
+                out.push_str(&format!("{open} {} {close} ", normalized(group.stream())));
+            }
+            TokenTree::Punct(punct) => out.push_str(&format!("{} ", punct.as_char())),
+            other => out.push_str(&format!("{other} ")),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(src: &str) -> u64 {
+        let imp: ItemImpl = syn::parse_str(src).unwrap();
+        impl_hash(&imp, &quote::quote!(nonsync))
+    }
+
+    #[test]
+    fn hashes_what_the_trait_depends_on() {
+        let original = hash("impl Mod for ModImpl { fn foo(&self, s: &str) -> u32 { 1 } }");
+
+        // spacing, docs and bodies of required methods don't matter
+        assert_eq!(
+            original,
+            hash(
+                "impl Mod for ModImpl {\n    /// Docs\n    fn foo( & self , s : &str )->u32 { 2 }\n}"
+            )
+        );
+
+        assert_ne!(
+            original,
+            hash("impl Mod for ModImpl { fn foo(&self, s: &str) -> u64 { 1 } }")
+        );
+        assert_ne!(
+            original,
+            hash("impl Mod for ModImpl { #[dylo::provided] fn foo(&self, s: &str) -> u32 { 1 } }")
+        );
+        assert_ne!(
+            original,
+            hash("impl Mod for ModImpl { type Error = (); fn foo(&self, s: &str) -> u32 { 1 } }")
+        );
+    }
+
+    #[test]
+    fn hashes_cfgs_of_items() {
+        let original = hash("impl Mod for ModImpl { type T = (); fn foo(&self) {} }");
+        for changed in [
+            "impl Mod for ModImpl { type T = (); #[cfg(feature = \"x\")] fn foo(&self) {} }",
+            "impl Mod for ModImpl { type T = (); #[cfg_attr(unix, deprecated)] fn foo(&self) {} }",
+            "impl Mod for ModImpl { #[cfg(unix)] type T = (); fn foo(&self) {} }",
+        ] {
+            assert_ne!(original, hash(changed), "{changed}");
+        }
+
+        // other attributes don't change the trait
+        assert_eq!(
+            original,
+            hash("impl Mod for ModImpl { type T = (); #[inline] fn foo(&self) {} }")
+        );
+    }
+
+    #[test]
+    fn hash_consts_are_named_after_the_trait() {
+        let name = |trait_ident: &str| {
+            hash_const_ident(&syn::parse_str::<syn::Ident>(trait_ident).unwrap()).to_string()
+        };
+        assert_eq!(name("Mod"), "__DYLO_SPEC_HASH_Mod");
+        assert_ne!(name("Foo"), name("FOO"));
+        assert_eq!(name("r#try"), "__DYLO_SPEC_HASH_try");
+    }
+
+    #[test]
+    fn token_spacing_does_not_matter() {
+        // rustc hands the macro `nonsync , name = "X"`, dylo-cli reads `nonsync, name = "X"`
+        let imp: ItemImpl = syn::parse_str("impl Mod for ModImpl {}").unwrap();
+        let from_source: proc_macro2::TokenStream = "nonsync, name = \"X\"".parse().unwrap();
+        let respaced: proc_macro2::TokenStream = "nonsync ,name=\"X\"".parse().unwrap();
+        assert_eq!(impl_hash(&imp, &from_source), impl_hash(&imp, &respaced));
+        assert_ne!(
+            impl_hash(&imp, &from_source),
+            impl_hash(&imp, &proc_macro2::TokenStream::new())
+        );
+    }
+}
@@ -0,0 +1,85 @@
+//! Instantiations of exported impls that are generic over types:
+//! `#[dylo::export(instantiate(T = User, T = Session))]`.
+//!
+//! dylo-cli checks, where the trait is declared, that each instantiation makes sense
+//! to consumers. `#[dylo::export]` checks that the impl actually applies to it.
+
+use syn::{
+    Ident, Token, Type,
+    parse::{Parse, ParseStream},
+    punctuated::Punctuated,
+    visit_mut::{self, VisitMut},
+};
+
+/// One set of types for the impl's type parameters: `T = User`, or `(K = u64, V = User)`.
+pub struct Instantiation {
+    pub bindings: Vec<(Ident, Type)>,
+}
+
+impl Parse for Instantiation {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        fn binding(input: ParseStream) -> syn::Result<(Ident, Type)> {
+            let param: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            Ok((param, input.parse()?))
+        }
+
+        let bindings = if input.peek(syn::token::Paren) {
+            let content;
+            syn::parenthesized!(content in input);
+            Punctuated::<_, Token![,]>::parse_terminated_with(&content, binding)?
+                .into_iter()
+                .collect()
+        } else {
+            vec![binding(input)?]
+        };
+        Ok(Self { bindings })
+    }
+}
+
+/// Replaces the type parameters of an instantiation with their types, including
+/// in paths like `T::Assoc`.
+pub struct Substitute<'a>(pub &'a [(Ident, Type)]);
+
+impl VisitMut for Substitute<'_> {
+    fn visit_type_mut(&mut self, ty: &mut Type) {
+        if let Type::Path(type_path) = ty {
+            if type_path.qself.is_none() && type_path.path.leading_colon.is_none() {
+                let path = &type_path.path;
+                let first = path.segments.first().filter(|s| s.arguments.is_none());
+                let binding =
+                    first.and_then(|first| self.0.iter().find(|(param, _)| *param == first.ident));
+                if let Some((_, replacement)) = binding {
+                    if path.segments.len() == 1 {
+                        *ty = replacement.clone();
+                    } else {
+                        // `T::Assoc` becomes `<User>::Assoc`
+                        let rest = path.segments.iter().skip(1);
+                        *ty = syn::parse_quote!(<#replacement> #(::#rest)*);
+                    }
+                    return;
+                }
+            }
+        }
+        visit_mut::visit_type_mut(self, ty);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quote::ToTokens as _;
+    use syn::visit_mut::VisitMut as _;
+
+    use super::*;
+
+    #[test]
+    fn substitutes_type_parameters() {
+        let instantiation: Instantiation = syn::parse_quote!(T = User);
+        let mut path: syn::Path = syn::parse_quote!(Store<T, Output = Vec<T>, Id = T::Id>);
+        Substitute(&instantiation.bindings).visit_path_mut(&mut path);
+        assert_eq!(
+            path.to_token_stream().to_string(),
+            "Store < User , Output = Vec < User > , Id = < User > :: Id >"
+        );
+    }
+}
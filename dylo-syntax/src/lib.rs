@@ -0,0 +1,25 @@
+#![doc = include_str!("../README.md")]
+
+pub mod args;
+pub mod asyncfn;
+pub mod drift;
+pub mod instantiate;
+
+/// Recognizes `#[dylo::export]`, with or without arguments.
+pub fn is_dylo_export(attr: &syn::Attribute) -> bool {
+    let segments = &attr.path().segments;
+    segments.len() == 2 && segments[0].ident == "dylo" && segments[1].ident == "export"
+}
+
+/// Recognizes `#[dylo::provided]`, on methods of exported impls.
+pub fn is_dylo_provided(attr: &syn::Attribute) -> bool {
+    let segments = &attr.path().segments;
+    segments.len() == 2 && segments[0].ident == "dylo" && segments[1].ident == "provided"
+}
+
+/// FNV-1a: stable across Rust versions and platforms, unlike `DefaultHasher`.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x100000001b3)
+    })
+}
@@ -0,0 +1,42 @@
+//! `async fn` in exported impls: they're not dyn-compatible, so dylo-cli declares
+//! them in the generated trait as returning a boxed future instead, and this
+//! rewrites the impl to match, see `dylo_syntax::asyncfn`.
+
+use dylo_syntax::asyncfn::desugar_async_signature;
+use proc_macro2::Span;
+use syn::{FnArg, Generics, ImplItemFn, ReturnType, Type};
+
+/// Rewrites an `async fn` from an exported impl so its signature matches the
+/// generated trait, boxing its body.
+pub(crate) fn desugar_async_fn(f: &mut ImplItemFn, outer: &Generics, send: bool) {
+    let output: Type = match &f.sig.output {
+        ReturnType::Default => syn::parse_quote!(()),
+        ReturnType::Type(_, ty) => (**ty).clone(),
+    };
+    let mut sig = desugar_async_signature(&f.sig, outer, send);
+
+    // arguments get moved into the future, like they would with `async fn`. Patterns
+    // (including `mut x`) are bound again inside the future, so they don't have to
+    // match the trait's.
+    let mut bindings = Vec::new();
+    for (i, input) in sig.inputs.iter_mut().enumerate() {
+        if let FnArg::Typed(pat_type) = input {
+            let arg = syn::Ident::new(&format!("__dylo_arg{i}"), Span::call_site());
+            let pat = std::mem::replace(&mut *pat_type.pat, syn::parse_quote!(#arg));
+            bindings.push(quote::quote! { let #pat = #arg; });
+        }
+    }
+
+    let body = &f.block;
+    f.sig = sig;
+    f.block = syn::parse_quote! {
+        {
+            ::std::boxed::Box::pin(async move {
+                #(#bindings)*
+                let __dylo_ret: #output = #body;
+                #[allow(unreachable_code)]
+                __dylo_ret
+            })
+        }
+    };
+}
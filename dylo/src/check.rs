@@ -0,0 +1,239 @@
+//! Compile-time checks of `#[dylo::export]` impls, so that mistakes show up in the mod
+//! itself rather than when dylo-cli runs, or when the consumer fails to build.
+//!
+//! These mirror the dyn-compatibility checks of `dylo-cli/src/lint.rs`, minus what
+//! needs to know about the rest of the crate: keep them in sync.
+
+use proc_macro2::Span;
+use syn::{
+    FnArg, GenericParam, ImplItem, ItemImpl, ReturnType, Signature, Type, spanned::Spanned as _,
+    visit::Visit,
+};
+
+/// Reports everything about `imp` that can't be part of a trait object.
+pub(crate) fn check_impl(imp: &ItemImpl) -> Vec<syn::Error> {
+    let mut errors = Vec::new();
+    if imp.trait_.is_none() {
+        errors.push(error(
+            imp.self_ty.span(),
+            "`#[dylo::export]` only works on trait impls",
+            "write `impl Trait for Type`: dylo-cli generates `Trait` from it",
+        ));
+        return errors;
+    }
+
+    for item in &imp.items {
+        match item {
+            ImplItem::Fn(method) => check_signature(&method.sig, &mut errors),
+            ImplItem::Const(constant) => errors.push(error(
+                constant.span(),
+                format!(
+                    "`{}` is an associated const, which is not dyn-compatible",
+                    constant.ident
+                ),
+                "turn it into a method instead",
+            )),
+            ImplItem::Type(assoc) if !assoc.generics.params.is_empty() => errors.push(error(
+                assoc.generics.span(),
+                format!("`{}` is a generic associated type", assoc.ident),
+                "generic associated types are not dyn-compatible, drop the parameters",
+            )),
+            _ => {}
+        }
+    }
+    errors
+}
+
+fn check_signature(sig: &Signature, errors: &mut Vec<syn::Error>) {
+    let name = &sig.ident;
+
+    let requires_sized = sig.generics.where_clause.as_ref().is_some_and(|wc| {
+        wc.predicates.iter().any(|predicate| match predicate {
+            syn::WherePredicate::Type(pt) => {
+                matches!(&pt.bounded_ty, Type::Path(p) if p.path.is_ident("Self"))
+                    && pt.bounds.iter().any(|bound| match bound {
+                        syn::TypeParamBound::Trait(t) => t.path.is_ident("Sized"),
+                        _ => false,
+                    })
+            }
+            _ => false,
+        })
+    });
+    if requires_sized {
+        // not part of the vtable, anything goes
+        return;
+    }
+
+    for param in &sig.generics.params {
+        if !matches!(param, GenericParam::Lifetime(_)) {
+            errors.push(error(
+                param.span(),
+                format!("`{name}` has generic type parameters"),
+                "take a `&dyn Trait` or `Box<dyn Trait>` instead",
+            ));
+        }
+    }
+
+    match sig.inputs.first() {
+        Some(FnArg::Receiver(receiver)) => {
+            if receiver.reference.is_none() && receiver.colon_token.is_none() {
+                errors.push(error(
+                    receiver.span(),
+                    format!("`{name}` takes `self` by value"),
+                    "take `self: Box<Self>` instead (or `&self` / `&mut self`)",
+                ));
+            }
+        }
+        _ => errors.push(error(
+            name.span(),
+            format!("`{name}` has no `self` receiver"),
+            "add a `&self` receiver, or a `where Self: Sized` bound",
+        )),
+    }
+
+    for input in &sig.inputs {
+        if let FnArg::Typed(pat_type) = input {
+            if let Some(span) = find_impl_trait(&pat_type.ty) {
+                errors.push(error(
+                    span,
+                    format!("`{name}` takes an `impl Trait` argument"),
+                    "take a `&dyn Trait` or `Box<dyn Trait>` instead",
+                ));
+            }
+            if is_bare_self(&pat_type.ty) {
+                errors.push(error(
+                    pat_type.ty.span(),
+                    format!("`{name}` takes `Self` by value"),
+                    "take `Box<Self>` or `&Self` instead",
+                ));
+            }
+        }
+    }
+
+    if let ReturnType::Type(_, ty) = &sig.output {
+        if let Some(span) = find_impl_trait(ty) {
+            errors.push(error(
+                span,
+                format!("`{name}` returns `impl Trait`"),
+                "return a `Box<dyn Trait>` instead",
+            ));
+        }
+        if is_bare_self(ty) {
+            errors.push(error(
+                ty.span(),
+                format!("`{name}` returns `Self` by value"),
+                "return `Box<Self>` instead",
+            ));
+        }
+    }
+}
+
+fn error(span: Span, message: impl std::fmt::Display, help: &str) -> syn::Error {
+    syn::Error::new(span, format!("{message}: {help}"))
+}
+
+fn is_bare_self(ty: &Type) -> bool {
+    matches!(ty, Type::Path(p) if p.qself.is_none() && p.path.is_ident("Self"))
+}
+
+fn find_impl_trait(ty: &Type) -> Option<Span> {
+    struct Finder(Option<Span>);
+
+    impl<'ast> Visit<'ast> for Finder {
+        fn visit_type_impl_trait(&mut self, i: &'ast syn::TypeImplTrait) {
+            self.0.get_or_insert(i.span());
+        }
+    }
+
+    let mut finder = Finder(None);
+    finder.visit_type(ty);
+    finder.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(src: &str) -> Vec<String> {
+        let imp: ItemImpl = syn::parse_str(src).unwrap();
+        check_impl(&imp).iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn accepts_dyn_compatible_impls() {
+        assert!(
+            check(
+                "impl Mod for ModImpl {
+                    type Output = u32;
+                    fn a(&self, s: &str) -> Box<dyn Fn(u32) -> u32> { todo!() }
+                    fn b(&mut self, f: &dyn Fn()) {}
+                    fn c(self: Box<Self>) -> Box<Self> { self }
+                    fn d<'a>(&'a self, x: &'a Self) -> &'a Self { x }
+                    fn e(self) -> impl Sized where Self: Sized {}
+                }"
+            )
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn rejects_inherent_impls() {
+        assert_eq!(
+            check("impl ModImpl { fn a(&self) {} }"),
+            [
+                "`#[dylo::export]` only works on trait impls: write `impl Trait for Type`: dylo-cli generates `Trait` from it"
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_associated_consts_and_gats() {
+        assert_eq!(
+            check("impl Mod for ModImpl { const N: u32 = 1; type Item<'a> = &'a str; }"),
+            [
+                "`N` is an associated const, which is not dyn-compatible: turn it into a method instead",
+                "`Item` is a generic associated type: generic associated types are not dyn-compatible, drop the parameters",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_signatures_that_are_not_dyn_compatible() {
+        for (method, expected) in [
+            (
+                "fn a<T>(&self, t: T) {}",
+                "`a` has generic type parameters: take a `&dyn Trait` or `Box<dyn Trait>` instead",
+            ),
+            (
+                "fn a(self) {}",
+                "`a` takes `self` by value: take `self: Box<Self>` instead (or `&self` / `&mut self`)",
+            ),
+            (
+                "fn a() {}",
+                "`a` has no `self` receiver: add a `&self` receiver, or a `where Self: Sized` bound",
+            ),
+            (
+                "fn a(&self, f: Vec<impl Fn()>) {}",
+                "`a` takes an `impl Trait` argument: take a `&dyn Trait` or `Box<dyn Trait>` instead",
+            ),
+            (
+                "fn a(&self, other: Self) {}",
+                "`a` takes `Self` by value: take `Box<Self>` or `&Self` instead",
+            ),
+            (
+                "fn a(&self) -> impl Iterator<Item = u32> {}",
+                "`a` returns `impl Trait`: return a `Box<dyn Trait>` instead",
+            ),
+            (
+                "fn a(&self) -> Self {}",
+                "`a` returns `Self` by value: return `Box<Self>` instead",
+            ),
+        ] {
+            assert_eq!(
+                check(&format!("impl Mod for ModImpl {{ {method} }}")),
+                [expected],
+                "{method}"
+            );
+        }
+    }
+}
@@ -0,0 +1,29 @@
+//! Detects an out-of-date `spec.rs` when the mod builds: next to each trait, dylo-cli
+//! records a hash of the exported impl it was generated from, and this asserts that
+//! the impl still hashes to it. See `dylo_syntax::drift`.
+
+use dylo_syntax::drift::{hash_const_ident, impl_hash};
+use syn::{Item, ItemImpl};
+
+/// Asserts that `imp` (not yet modified by the macro) hashes to what dylo-cli recorded,
+/// see [`hash_const_ident`]. `trait_ident` is the name of the generated trait.
+pub(crate) fn check_hash(
+    imp: &ItemImpl,
+    args: &proc_macro2::TokenStream,
+    trait_ident: &syn::Ident,
+) -> Item {
+    let ident = hash_const_ident(trait_ident);
+    let hash = syn::LitInt::new(
+        &format!("0x{:016x}", impl_hash(imp, args)),
+        proc_macro2::Span::call_site(),
+    );
+    let span = imp.impl_token.span;
+    let cfgs = imp.attrs.iter().filter(|attr| attr.path().is_ident("cfg"));
+    syn::parse_quote_spanned! {span=>
+        #(#cfgs)*
+        const _: () = ::std::assert!(
+            #ident == #hash,
+            "spec.rs is out of date, run `dylo gen`",
+        );
+    }
+}
@@ -0,0 +1,55 @@
+//! Instantiations of impls that are generic over types:
+//! `#[dylo::export(instantiate(T = User, T = Session))]`, as parsed by
+//! `dylo_syntax::instantiate`.
+//!
+//! dylo-cli checks that each instantiation makes sense to consumers, this checks that
+//! the impl actually applies to it.
+
+use dylo_syntax::instantiate::Substitute;
+use syn::{Ident, ImplItem, Item, ItemImpl, Type, punctuated::Punctuated, visit_mut::VisitMut};
+
+/// Coerces a box of the impl's self type (with `bindings` substituted) into a box of the
+/// trait object consumers get, which only compiles if the impl applies to it and the
+/// trait is dyn-compatible. Impls without type parameters are checked with no bindings.
+pub(crate) fn coercion(imp: &ItemImpl, bindings: &[(Ident, Type)]) -> Option<Item> {
+    let (_, trait_path, _) = imp.trait_.as_ref()?;
+    let mut trait_path = trait_path.clone();
+
+    // `dyn Trait` must name its associated types
+    let assoc_types = imp.items.iter().filter_map(|item| match item {
+        ImplItem::Type(assoc) => {
+            let (ident, ty) = (&assoc.ident, &assoc.ty);
+            Some(syn::parse_quote!(#ident = #ty))
+        }
+        _ => None,
+    });
+    let last = trait_path.segments.last_mut()?;
+    let mut args = match std::mem::take(&mut last.arguments) {
+        syn::PathArguments::AngleBracketed(args) => args.args,
+        _ => Punctuated::new(),
+    };
+    args.extend(assoc_types.collect::<Vec<syn::GenericArgument>>());
+    if !args.is_empty() {
+        last.arguments = syn::PathArguments::AngleBracketed(syn::parse_quote!(<#args>));
+    }
+
+    let mut self_ty = (*imp.self_ty).clone();
+    let mut dyn_type: Type = syn::parse_quote!(dyn #trait_path);
+    let mut substitute = Substitute(bindings);
+    substitute.visit_type_mut(&mut self_ty);
+    substitute.visit_type_mut(&mut dyn_type);
+
+    let lifetimes = imp.generics.lifetimes();
+    let cfgs = imp.attrs.iter().filter(|attr| attr.path().is_ident("cfg"));
+    Some(syn::parse_quote! {
+        #(#cfgs)*
+        const _: () = {
+            #[allow(dead_code)]
+            fn __dylo_instantiate<#(#lifetimes),*>(
+                imp: ::std::boxed::Box<#self_ty>,
+            ) -> ::std::boxed::Box<#dyn_type> {
+                imp
+            }
+        };
+    })
+}
@@ -1,6 +1,229 @@
+use dylo_syntax::is_dylo_provided;
 use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
 
+mod asyncfn;
+mod check;
+mod drift;
+mod instantiate;
+
+/// Marks an impl whose trait dylo-cli generates, see the crate's README.
+///
+/// The arguments and the impl are validated here, so that mistakes (unknown arguments,
+/// signatures that aren't dyn-compatible, inherent impls) are reported as soon as the
+/// mod builds, and the impl is coerced to the trait object consumers get, which has
+/// rustc check the rest. `spec.rs` being out of date is reported too.
+///
+/// The impl is then rewritten to implement the trait dylo-cli generates, rather than
+/// the one it was written against:
+///
+///   * attributes that belong to the trait, not the impl, are stripped from the impl
+///     and its items: `#[deprecated]`, `#[must_use]`, and the ones listed in
+///     `forward(...)`
+///   * with `name = "..."`, the implemented trait is renamed to the generated one
+///   * `#[dylo::provided]` methods are removed: the trait has them as default methods
+///   * `async fn`s are rewritten to return a boxed future, like the trait declares them
+#[proc_macro_attribute]
+pub fn export(attr: TokenStream, item: TokenStream) -> TokenStream {
+    export_impl(attr.into(), item.into()).into()
+}
+
+fn export_impl(attr: TokenStream2, item: TokenStream2) -> TokenStream2 {
+    let mut imp = match syn::parse2::<syn::Item>(item.clone()) {
+        Ok(syn::Item::Impl(imp)) => imp,
+        Ok(other) => {
+            let e = syn::Error::new_spanned(
+                other,
+                "`#[dylo::export]` only works on trait impls: `impl Trait for Type`",
+            );
+            let mut out = e.to_compile_error();
+            out.extend(item);
+            return out;
+        }
+        // not ours to complain about: rustc will
+        Err(_) => return item,
+    };
+    let args = match syn::parse2::<dylo_syntax::args::ExportArgs>(attr.clone()) {
+        Ok(args) => args,
+        Err(e) => {
+            let mut out = e.to_compile_error();
+            out.extend(item);
+            return out;
+        }
+    };
+    let errors = check::check_impl(&imp);
+    if imp.trait_.is_none() {
+        let mut out: TokenStream2 = errors.iter().map(syn::Error::to_compile_error).collect();
+        out.extend(item);
+        return out;
+    }
+
+    // computed on the impl as written, which is what dylo-cli sees
+    let hash_check = imp.trait_.as_ref().and_then(|(_, trait_path, _)| {
+        let trait_ident = args
+            .name
+            .as_ref()
+            .or_else(|| trait_path.segments.last().map(|segment| &segment.ident))?;
+        Some(drift::check_hash(&imp, &attr, trait_ident))
+    });
+
+    // rustc rejects (or ignores) these on trait impls: dylo-cli puts them on the trait
+    let forward: Vec<String> = args
+        .forward
+        .iter()
+        .map(|path| quote::ToTokens::to_token_stream(path).to_string())
+        .collect();
+    let belongs_to_trait = |attr: &syn::Attribute| {
+        let path = attr.path();
+        path.is_ident("deprecated")
+            || path.is_ident("must_use")
+            || forward.contains(&quote::ToTokens::to_token_stream(path).to_string())
+    };
+    imp.attrs.retain(|attr| !belongs_to_trait(attr));
+
+    if let (Some(name), Some((_, trait_path, _))) = (&args.name, &mut imp.trait_) {
+        if let Some(last) = trait_path.segments.last_mut() {
+            last.ident = name.clone();
+        }
+    }
+
+    imp.items.retain(|item| match item {
+        syn::ImplItem::Fn(f) => !f.attrs.iter().any(is_dylo_provided),
+        _ => true,
+    });
+
+    let generics = imp.generics.clone();
+    for item in &mut imp.items {
+        match item {
+            syn::ImplItem::Fn(f) => {
+                f.attrs.retain(|attr| !belongs_to_trait(attr));
+                if f.sig.asyncness.is_some() {
+                    asyncfn::desugar_async_fn(f, &generics, args.interface.send_futures());
+                }
+            }
+            syn::ImplItem::Type(t) => t.attrs.retain(|attr| !belongs_to_trait(attr)),
+            _ => {}
+        }
+    }
+
+    // impls generic over types are checked once per instantiation
+    let mut checks: Vec<syn::Item> = if imp.generics.type_params().next().is_none() {
+        instantiate::coercion(&imp, &[]).into_iter().collect()
+    } else {
+        args.instantiations
+            .iter()
+            .filter_map(|instantiation| instantiate::coercion(&imp, &instantiation.bindings))
+            .collect()
+    };
+    checks.extend(hash_check);
+    let mut out: TokenStream2 = errors.iter().map(syn::Error::to_compile_error).collect();
+    quote::ToTokens::to_tokens(&imp, &mut out);
+    out.extend(checks.iter().map(quote::ToTokens::to_token_stream));
+    out
+}
+
+/// Marks a method of a `#[dylo::export]` impl as provided: dylo-cli emits it as a
+/// default method of the generated trait, so other implementations (like mocks)
+/// don't have to. `#[dylo::export]` takes care of it, this only reports misuse.
 #[proc_macro_attribute]
-pub fn export(_attr: TokenStream, item: TokenStream) -> TokenStream {
-    item
+pub fn provided(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut out = TokenStream::from(
+        syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "`#[dylo::provided]` only works on methods of `#[dylo::export]` impls",
+        )
+        .to_compile_error(),
+    );
+    out.extend(item);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use quote::ToTokens as _;
+
+    use super::*;
+
+    /// Expands `#[dylo::export(attr)]` on `item`, returning the rewritten impl (the
+    /// first item) as a string.
+    fn expand(attr: &str, item: &str) -> String {
+        let out = export_impl(attr.parse().unwrap(), item.parse().unwrap());
+        let file: syn::File = syn::parse2(out).unwrap();
+        file.items[0].to_token_stream().to_string()
+    }
+
+    #[test]
+    fn strips_attributes_that_belong_to_the_trait() {
+        let out = expand(
+            "forward(tracing::instrument)",
+            "#[must_use] #[deprecated] #[derive_more] impl Mod for ModImpl { #[deprecated] #[inline] #[tracing::instrument] fn f(&self) {} }",
+        );
+        assert_eq!(
+            out,
+            "# [derive_more] impl Mod for ModImpl { # [inline] fn f (& self) { } }"
+        );
+    }
+
+    #[test]
+    fn renames_the_trait() {
+        let out = expand(r#"name = "Other""#, "impl crate::Mod for ModImpl {}");
+        assert_eq!(out, "impl crate :: Other for ModImpl { }");
+    }
+
+    #[test]
+    fn removes_provided_methods() {
+        let out = expand(
+            "",
+            "impl Mod for ModImpl { fn a(&self) {} #[dylo::provided] fn b(&self) { self.a() } }",
+        );
+        assert_eq!(out, "impl Mod for ModImpl { fn a (& self) { } }");
+    }
+
+    #[test]
+    fn rewrites_async_fns() {
+        let item = "impl Mod for ModImpl { async fn get(&self, mut n: u32) -> u32 { n += 1; n } }";
+
+        let out = expand("", item);
+        assert!(!out.contains("async fn"), "{out}");
+        assert!(
+            out.contains("fn get < 'life0 , 'dylo_async > (& 'life0 self , __dylo_arg1 : u32)"),
+            "{out}"
+        );
+        assert!(
+            out.contains(":: std :: marker :: Send + 'dylo_async"),
+            "{out}"
+        );
+        assert!(out.contains("let mut n = __dylo_arg1 ;"), "{out}");
+
+        // `nonsync` traits aren't `Sync`, so their futures needn't be `Send`
+        let out = expand("nonsync", item);
+        assert!(!out.contains(":: std :: marker :: Send"), "{out}");
+    }
+
+    #[test]
+    fn adds_checks_after_the_impl() {
+        let out = export_impl(
+            "".parse().unwrap(),
+            "impl Mod for ModImpl {}".parse().unwrap(),
+        );
+        let file: syn::File = syn::parse2(out).unwrap();
+        let checks: Vec<String> = file.items[1..]
+            .iter()
+            .map(|item| item.to_token_stream().to_string())
+            .collect();
+        assert_eq!(checks.len(), 2, "{checks:?}");
+        assert!(checks[0].contains("Box < dyn Mod >"), "{}", checks[0]);
+        assert!(checks[1].contains("__DYLO_SPEC_HASH_Mod"), "{}", checks[1]);
+    }
+
+    #[test]
+    fn leaves_invalid_input_alone() {
+        let out = export_impl(
+            "bogus".parse().unwrap(),
+            "impl Mod for ModImpl {}".parse().unwrap(),
+        );
+        let out = out.to_string();
+        assert!(out.contains("compile_error"), "{out}");
+        assert!(out.contains("impl Mod for ModImpl"), "{out}");
+    }
 }